@@ -1,6 +1,6 @@
 #!/usr/bin/bash
 
-for file in demo fwht styles 
+for file in demo fwht styles containers blocks
 do
     pandoc $file.md --filter ../target/debug/just-latex -o $file.html
 done
@@ -0,0 +1,57 @@
+//! External SVG assets.
+//!
+//! Instead of being stuffed into the HTML, rendered SVGs can be written to a directory served
+//! alongside the documents, so that browsers download and cache them like any other image. Files
+//! are named after a hash of their content: identical SVGs (e.g. a page shared by multiple builds of
+//! the same post) are written once, and a changed SVG never reuses the URL of a stale one.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use tempfile::NamedTempFile;
+
+pub struct AssetDir {
+    dir: PathBuf,
+    url_prefix: String,
+}
+
+impl AssetDir {
+    /// Opens (and creates if necessary) an asset directory whose files are served under the given
+    /// URL prefix.
+    pub fn new(dir: impl AsRef<Path>, url_prefix: &str) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("creating asset directory {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            url_prefix: url_prefix.trim_end_matches('/').into(),
+        })
+    }
+
+    /// Writes an SVG unless an identical one is already there, and returns its URL.
+    pub fn write_svg(&self, data: &[u8]) -> Result<String> {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+        let name = format!("{:016x}.svg", hasher.finish());
+        let path = self.dir.join(&name);
+        if !path.exists() {
+            // Written to a temporary file first, so concurrent pandoc runs sharing the directory
+            // never serve half-written files.
+            let mut temp = NamedTempFile::new_in(&self.dir)?;
+            temp.write_all(data)?;
+            temp.persist(&path)
+                .with_context(|| format!("writing asset {}", path.display()))?;
+        }
+        Ok(if self.url_prefix.is_empty() {
+            name
+        } else {
+            format!("{}/{}", self.url_prefix, name)
+        })
+    }
+}
@@ -0,0 +1,587 @@
+//! A typed model of the subset of the Pandoc JSON AST that JustLaTeX needs.
+//!
+//! Only containers that may (transitively) hold maths are modelled. Everything else, including
+//! node types introduced by future versions of Pandoc, is kept verbatim as a [`Value`] so that it
+//! survives the round trip untouched. Likewise attributes, alignments and the like are opaque. A
+//! modelled node whose content does not have the expected shape is an error, rather than being
+//! kept verbatim and its maths silently skipped.
+//!
+//! Both the table model of pandoc-types 1.21 onwards and the legacy one are supported. Which one a
+//! table follows is told by the `pandoc-api-version` of the document, so that the same tree works
+//! for all Pandoc versions since 1.18.
+//!
+//! The [`MutVisitor`] trait is similar in spirit to pandoc-ast's, except that nodes are borrowed
+//! for the lifetime of the tree. This allows visitors to keep the references and replace the
+//! nodes after the whole tree has been visited.
+
+use std::{cell, collections::BTreeMap};
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+thread_local! {
+    /// The `pandoc-api-version` (major and minor) of the document being deserialized, if any.
+    static API_VERSION: cell::Cell<Option<(u64, u64)>> = const { cell::Cell::new(None) };
+}
+
+#[derive(Debug, Serialize)]
+pub struct Pandoc {
+    #[serde(rename = "pandoc-api-version")]
+    pub api_version: Vec<u64>,
+    pub meta: BTreeMap<String, MetaValue>,
+    pub blocks: Vec<Block>,
+}
+
+impl<'de> Deserialize<'de> for Pandoc {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Document {
+            #[serde(rename = "pandoc-api-version")]
+            api_version: Vec<u64>,
+            meta: Value,
+            blocks: Value,
+        }
+
+        // The nodes are only read once the version is known, which is not necessarily the first
+        // field of the document.
+        let document = Document::deserialize(deserializer)?;
+        let version = match document.api_version[..] {
+            [major, minor, ..] => Some((major, minor)),
+            _ => None,
+        };
+        let outer = API_VERSION.with(|cell| cell.replace(version));
+        let nodes = BTreeMap::deserialize(&document.meta)
+            .and_then(|meta| Ok((meta, Vec::deserialize(&document.blocks)?)));
+        API_VERSION.with(|cell| cell.set(outer));
+        let (meta, blocks) = nodes.map_err(D::Error::custom)?;
+        Ok(Self {
+            api_version: document.api_version,
+            meta,
+            blocks,
+        })
+    }
+}
+
+/// Deserializes a node with the derived implementation if its tag is among the `modelled` ones,
+/// and keeps it verbatim with `other` otherwise.
+fn deserialize_node<'de, D: Deserializer<'de>, T>(
+    deserializer: D,
+    modelled: &[&str],
+    other: fn(Value) -> T,
+    derived: fn(&Value) -> serde_json::Result<T>,
+) -> Result<T, D::Error> {
+    let value = Value::deserialize(deserializer)?;
+    match value.get("t").and_then(Value::as_str) {
+        Some(tag) if modelled.contains(&tag) => {
+            derived(&value).map_err(|e| D::Error::custom(format!("malformed {} node: {}", tag, e)))
+        }
+        _ => Ok(other(value)),
+    }
+}
+
+/// Implements `Serialize` and `Deserialize` through the implementations derived with
+/// `#[serde(remote = "Self")]`, keeping nodes with tags other than the given ones verbatim in the
+/// `Other` variant. The variant is skipped by the derived implementations, as an untagged variant
+/// would swallow the errors of the modelled ones.
+macro_rules! node_serde {
+    ($ty:ident, [$($tag:ident),* $(,)?]) => {
+        impl Serialize for $ty {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                match self {
+                    $ty::Other(value) => value.serialize(serializer),
+                    _ => $ty::serialize(self, serializer),
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $ty {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let modelled = [$(stringify!($tag)),*];
+                deserialize_node(deserializer, &modelled, $ty::Other, |value| {
+                    $ty::deserialize(value)
+                })
+            }
+        }
+    };
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(remote = "Self", tag = "t", content = "c")]
+pub enum MetaValue {
+    MetaMap(BTreeMap<String, MetaValue>),
+    MetaList(Vec<MetaValue>),
+    MetaInlines(Vec<Inline>),
+    MetaBlocks(Vec<Block>),
+    /// `MetaBool` and `MetaString`.
+    #[serde(skip)]
+    Other(Value),
+}
+
+node_serde!(MetaValue, [MetaMap, MetaList, MetaInlines, MetaBlocks]);
+
+// Variant names follow Pandoc's.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(remote = "Self", tag = "t", content = "c")]
+pub enum Block {
+    Plain(Vec<Inline>),
+    Para(Vec<Inline>),
+    LineBlock(Vec<Vec<Inline>>),
+    RawBlock(String, String),
+    BlockQuote(Vec<Block>),
+    OrderedList(Value, Vec<Vec<Block>>),
+    BulletList(Vec<Vec<Block>>),
+    DefinitionList(Vec<(Vec<Inline>, Vec<Vec<Block>>)>),
+    Header(u64, Value, Vec<Inline>),
+    Table(Table),
+    /// Introduced in pandoc-types 1.23.
+    Figure(Value, Caption, Vec<Block>),
+    Div(Value, Vec<Block>),
+    /// Blocks that cannot contain maths.
+    #[serde(skip)]
+    Other(Value),
+}
+
+node_serde!(
+    Block,
+    [
+        Plain,
+        Para,
+        LineBlock,
+        RawBlock,
+        BlockQuote,
+        OrderedList,
+        BulletList,
+        DefinitionList,
+        Header,
+        Table,
+        Figure,
+        Div,
+    ]
+);
+
+// Variant names follow Pandoc's.
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(remote = "Self", tag = "t", content = "c")]
+pub enum Inline {
+    Emph(Vec<Inline>),
+    Underline(Vec<Inline>),
+    Strong(Vec<Inline>),
+    Strikeout(Vec<Inline>),
+    Superscript(Vec<Inline>),
+    Subscript(Vec<Inline>),
+    SmallCaps(Vec<Inline>),
+    Quoted(Value, Vec<Inline>),
+    Cite(Vec<Citation>, Vec<Inline>),
+    Math(MathType, String),
+    RawInline(String, String),
+    Link(Value, Vec<Inline>, Value),
+    Image(Value, Vec<Inline>, Value),
+    Note(Vec<Block>),
+    Span(Value, Vec<Inline>),
+    /// Inlines that cannot contain maths, e.g. `Str` and `Space`.
+    #[serde(skip)]
+    Other(Value),
+}
+
+node_serde!(
+    Inline,
+    [
+        Emph,
+        Underline,
+        Strong,
+        Strikeout,
+        Superscript,
+        Subscript,
+        SmallCaps,
+        Quoted,
+        Cite,
+        Math,
+        RawInline,
+        Link,
+        Image,
+        Note,
+        Span,
+    ]
+);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "t")]
+pub enum MathType {
+    InlineMath,
+    DisplayMath,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Citation {
+    #[serde(rename = "citationPrefix")]
+    pub prefix: Vec<Inline>,
+    #[serde(rename = "citationSuffix")]
+    pub suffix: Vec<Inline>,
+    /// The remaining fields, i.e. the id, mode, note number and hash.
+    #[serde(flatten)]
+    pub rest: Map<String, Value>,
+}
+
+/// A caption, consisting of an optional short caption and the caption itself.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Caption(pub Option<Vec<Inline>>, pub Vec<Block>);
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Table {
+    /// The table model since pandoc-types 1.21:
+    /// `Table Attr Caption [ColSpec] TableHead [TableBody] TableFoot`.
+    Current(Value, Caption, Value, TableHead, Vec<TableBody>, TableFoot),
+    /// The table model before pandoc-types 1.21:
+    /// `Table [Inline] [Alignment] [Double] [TableCell] [[TableCell]]`, where a cell is `[Block]`.
+    Legacy(
+        Vec<Inline>,
+        Value,
+        Value,
+        Vec<Vec<Block>>,
+        Vec<Vec<Vec<Block>>>,
+    ),
+}
+
+impl<'de> Deserialize<'de> for Table {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let version = API_VERSION.with(cell::Cell::get);
+        // Outside of a document, go by the number of fields.
+        let current = match version {
+            Some(version) => version >= (1, 21),
+            None => value.as_array().is_none_or(|fields| fields.len() == 6),
+        };
+        let table = if current {
+            Deserialize::deserialize(&value).map(|(attr, caption, specs, head, bodies, foot)| {
+                Self::Current(attr, caption, specs, head, bodies, foot)
+            })
+        } else {
+            Deserialize::deserialize(&value).map(|(caption, aligns, widths, head, rows)| {
+                Self::Legacy(caption, aligns, widths, head, rows)
+            })
+        };
+        table.map_err(|e| {
+            let model = if current {
+                "the table model of pandoc-types 1.21 onwards"
+            } else {
+                "the legacy table model of pandoc-types before 1.21"
+            };
+            let version = match version {
+                Some((major, minor)) => format!(" for pandoc-api-version {}.{}", major, minor),
+                None => String::new(),
+            };
+            D::Error::custom(format!("expected {}{}: {}", model, version, e))
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TableHead(pub Value, pub Vec<Row>);
+
+/// `TableBody Attr RowHeadColumns [Row] [Row]`, the rows being the intermediate head and the body.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TableBody(pub Value, pub Value, pub Vec<Row>, pub Vec<Row>);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TableFoot(pub Value, pub Vec<Row>);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Row(pub Value, pub Vec<Cell>);
+
+/// `Cell Attr Alignment RowSpan ColSpan [Block]`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Cell(pub Value, pub Value, pub Value, pub Value, pub Vec<Block>);
+
+/// Visits the tree, borrowing nodes for the lifetime `'a` of the tree.
+///
+/// The default implementations simply descend into children through [`walk_block`] and
+/// [`walk_inline`]. Implementors override them to intercept nodes, and call the `walk_*` functions
+/// themselves to keep descending.
+pub trait MutVisitor<'a> {
+    fn visit_block(&mut self, block: &'a mut Block) {
+        walk_block(self, block);
+    }
+
+    fn visit_inline(&mut self, inline: &'a mut Inline) {
+        walk_inline(self, inline);
+    }
+}
+
+/// Visits all inlines and blocks in a metadata value.
+pub fn walk_meta<'a, V: MutVisitor<'a> + ?Sized>(visitor: &mut V, value: &'a mut MetaValue) {
+    match value {
+        MetaValue::MetaMap(map) => map.values_mut().for_each(|value| walk_meta(visitor, value)),
+        MetaValue::MetaList(values) => values
+            .iter_mut()
+            .for_each(|value| walk_meta(visitor, value)),
+        MetaValue::MetaInlines(inlines) => walk_inlines(visitor, inlines),
+        MetaValue::MetaBlocks(blocks) => walk_blocks(visitor, blocks),
+        MetaValue::Other(_) => {}
+    }
+}
+
+/// Visits all children of a block.
+pub fn walk_block<'a, V: MutVisitor<'a> + ?Sized>(visitor: &mut V, block: &'a mut Block) {
+    match block {
+        Block::Plain(inlines) | Block::Para(inlines) | Block::Header(_, _, inlines) => {
+            walk_inlines(visitor, inlines)
+        }
+        Block::LineBlock(lines) => lines
+            .iter_mut()
+            .for_each(|inlines| walk_inlines(visitor, inlines)),
+        Block::BlockQuote(blocks) | Block::Div(_, blocks) => walk_blocks(visitor, blocks),
+        Block::OrderedList(_, items) | Block::BulletList(items) => items
+            .iter_mut()
+            .for_each(|blocks| walk_blocks(visitor, blocks)),
+        Block::DefinitionList(items) => {
+            for (term, definitions) in items {
+                walk_inlines(visitor, term);
+                definitions
+                    .iter_mut()
+                    .for_each(|blocks| walk_blocks(visitor, blocks));
+            }
+        }
+        Block::Table(Table::Current(_, caption, _, head, bodies, foot)) => {
+            walk_caption(visitor, caption);
+            walk_rows(visitor, &mut head.1);
+            for body in bodies {
+                walk_rows(visitor, &mut body.2);
+                walk_rows(visitor, &mut body.3);
+            }
+            walk_rows(visitor, &mut foot.1);
+        }
+        Block::Table(Table::Legacy(caption, _, _, head, rows)) => {
+            walk_inlines(visitor, caption);
+            head.iter_mut()
+                .chain(rows.iter_mut().flatten())
+                .for_each(|blocks| walk_blocks(visitor, blocks));
+        }
+        Block::Figure(_, caption, blocks) => {
+            walk_caption(visitor, caption);
+            walk_blocks(visitor, blocks);
+        }
+        Block::RawBlock(..) | Block::Other(_) => {}
+    }
+}
+
+/// Visits all children of an inline.
+pub fn walk_inline<'a, V: MutVisitor<'a> + ?Sized>(visitor: &mut V, inline: &'a mut Inline) {
+    match inline {
+        Inline::Emph(inlines)
+        | Inline::Underline(inlines)
+        | Inline::Strong(inlines)
+        | Inline::Strikeout(inlines)
+        | Inline::Superscript(inlines)
+        | Inline::Subscript(inlines)
+        | Inline::SmallCaps(inlines)
+        | Inline::Quoted(_, inlines)
+        | Inline::Link(_, inlines, _)
+        | Inline::Image(_, inlines, _)
+        | Inline::Span(_, inlines) => walk_inlines(visitor, inlines),
+        Inline::Cite(citations, inlines) => {
+            for citation in citations {
+                walk_inlines(visitor, &mut citation.prefix);
+                walk_inlines(visitor, &mut citation.suffix);
+            }
+            walk_inlines(visitor, inlines);
+        }
+        Inline::Note(blocks) => walk_blocks(visitor, blocks),
+        Inline::Math(..) | Inline::RawInline(..) | Inline::Other(_) => {}
+    }
+}
+
+pub fn walk_blocks<'a, V: MutVisitor<'a> + ?Sized>(visitor: &mut V, blocks: &'a mut [Block]) {
+    blocks
+        .iter_mut()
+        .for_each(|block| visitor.visit_block(block));
+}
+
+pub fn walk_inlines<'a, V: MutVisitor<'a> + ?Sized>(visitor: &mut V, inlines: &'a mut [Inline]) {
+    inlines
+        .iter_mut()
+        .for_each(|inline| visitor.visit_inline(inline));
+}
+
+fn walk_caption<'a, V: MutVisitor<'a> + ?Sized>(visitor: &mut V, caption: &'a mut Caption) {
+    if let Some(short) = &mut caption.0 {
+        walk_inlines(visitor, short);
+    }
+    walk_blocks(visitor, &mut caption.1);
+}
+
+fn walk_rows<'a, V: MutVisitor<'a> + ?Sized>(visitor: &mut V, rows: &'a mut [Row]) {
+    for row in rows {
+        for cell in row.1.iter_mut() {
+            walk_blocks(visitor, &mut cell.4);
+        }
+    }
+}
+
+/// Converts inlines to plain text, dropping all formatting, like Pandoc's `stringify`. Maths and
+/// raw inlines are kept as their source.
+pub fn stringify(inlines: &[Inline]) -> String {
+    let mut text = String::new();
+    for inline in inlines {
+        match inline {
+            Inline::Emph(inlines)
+            | Inline::Underline(inlines)
+            | Inline::Strong(inlines)
+            | Inline::Strikeout(inlines)
+            | Inline::Superscript(inlines)
+            | Inline::Subscript(inlines)
+            | Inline::SmallCaps(inlines)
+            | Inline::Quoted(_, inlines)
+            | Inline::Cite(_, inlines)
+            | Inline::Link(_, inlines, _)
+            | Inline::Image(_, inlines, _)
+            | Inline::Span(_, inlines) => text.push_str(&stringify(inlines)),
+            Inline::Math(_, src) | Inline::RawInline(_, src) => text.push_str(src),
+            Inline::Note(_) => {}
+            Inline::Other(value) => match value["t"].as_str() {
+                Some("Str") | Some("Code") => {
+                    let c = &value["c"];
+                    text.push_str(c.as_str().or_else(|| c[1].as_str()).unwrap_or_default());
+                }
+                Some("Space") | Some("SoftBreak") | Some("LineBreak") => text.push(' '),
+                _ => {}
+            },
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn math(src: &str) -> Value {
+        json!({"t": "Math", "c": [{"t": "InlineMath"}, src]})
+    }
+
+    fn plain(src: &str) -> Value {
+        json!({"t": "Plain", "c": [math(src)]})
+    }
+
+    fn document(version: [u64; 3], blocks: Value) -> Value {
+        json!({"pandoc-api-version": version, "meta": {}, "blocks": blocks})
+    }
+
+    /// Counts the maths reached by walking the tree.
+    fn count_maths(tree: &mut Pandoc) -> usize {
+        struct MathCounter(usize);
+
+        impl<'a> MutVisitor<'a> for MathCounter {
+            fn visit_inline(&mut self, inline: &'a mut Inline) {
+                if let Inline::Math(..) = inline {
+                    self.0 += 1;
+                }
+                walk_inline(self, inline);
+            }
+        }
+
+        let mut counter = MathCounter(0);
+        tree.meta
+            .values_mut()
+            .for_each(|value| walk_meta(&mut counter, value));
+        walk_blocks(&mut counter, &mut tree.blocks);
+        counter.0
+    }
+
+    #[test]
+    fn round_trip() {
+        let attr = json!(["", [], []]);
+        let cell = |src: &str| json!([attr, {"t": "AlignDefault"}, 1, 1, [plain(src)]]);
+        let value = json!({
+            "pandoc-api-version": [1, 23, 1],
+            "meta": {
+                "title": {"t": "MetaInlines", "c": [{"t": "Str", "c": "On"}, math("x")]},
+                "draft": {"t": "MetaBool", "c": false},
+                "tags": {"t": "MetaList", "c": [{"t": "MetaString", "c": "maths"}]},
+                "extra": {"t": "MetaMap", "c": {
+                    "abstract": {"t": "MetaBlocks", "c": [plain("y")]}
+                }}
+            },
+            "blocks": [
+                {"t": "Para", "c": [
+                    {"t": "Str", "c": "Let"},
+                    {"t": "Space"},
+                    math("z"),
+                    {"t": "Frobnicate", "c": [1, {"t": "Str", "c": "?"}]}
+                ]},
+                {"t": "HorizontalRule"},
+                {"t": "Frobnicate", "c": {"nested": [math("not a node")]}},
+                {"t": "Table", "c": [
+                    attr,
+                    [[{"t": "Str", "c": "short"}], [plain("caption")]],
+                    [[{"t": "AlignDefault"}, {"t": "ColWidthDefault"}]],
+                    [attr, [[attr, [cell("head")]]]],
+                    [[attr, 0, [[attr, [cell("intermediate")]]], [[attr, [cell("body")]]]]],
+                    [attr, [[attr, [cell("foot")]]]]
+                ]},
+                {"t": "Figure", "c": [
+                    attr,
+                    [null, [plain("figure caption")]],
+                    [plain("figure")]
+                ]}
+            ]
+        });
+        let mut tree: Pandoc = serde_json::from_value(value.clone()).unwrap();
+        // Everything but the math inside the unknown block.
+        assert_eq!(count_maths(&mut tree), 10);
+        assert!(matches!(tree.blocks[3], Block::Table(Table::Current(..))));
+        assert_eq!(serde_json::to_value(&tree).unwrap(), value);
+
+        let value = document(
+            [1, 20, 0],
+            json!([{"t": "Table", "c": [
+                [math("legacy caption")],
+                [{"t": "AlignDefault"}],
+                [0],
+                [[plain("legacy head")]],
+                [[[plain("legacy body")]]]
+            ]}]),
+        );
+        let mut tree: Pandoc = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(count_maths(&mut tree), 3);
+        assert!(matches!(tree.blocks[0], Block::Table(Table::Legacy(..))));
+        assert_eq!(serde_json::to_value(&tree).unwrap(), value);
+    }
+
+    #[test]
+    fn malformed_nodes() {
+        let error = |value: Value| {
+            serde_json::from_value::<Pandoc>(value)
+                .unwrap_err()
+                .to_string()
+        };
+
+        let message = error(document(
+            [1, 23, 1],
+            json!([{"t": "Div", "c": [["", [], []], [{"t": "Para", "c": [{"t": "Math", "c": 1}]}]]}]),
+        ));
+        assert!(message.contains("malformed Div node: malformed Para node: malformed Math node"));
+
+        let message = error(document(
+            [1, 23, 1],
+            json!([{"t": "Table", "c": [1, [{"x": 1}]]}]),
+        ));
+        assert!(message.contains("malformed Table node"));
+        assert!(message.contains("table model of pandoc-types 1.21 onwards"));
+        assert!(message.contains("pandoc-api-version 1.23"));
+
+        // A table of the current model in an older document.
+        let message = error(document(
+            [1, 20, 0],
+            json!([{"t": "Table", "c": [
+                ["", [], []], [null, []], [], [["", [], []], []], [], [["", [], []], []]
+            ]}]),
+        ));
+        assert!(message.contains("legacy table model of pandoc-types before 1.21"));
+        assert!(message.contains("pandoc-api-version 1.20"));
+    }
+}
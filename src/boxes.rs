@@ -0,0 +1,53 @@
+//! Typesetting fragments into boxes, for the positionings that locate fragments by their boxes
+//! rather than through SyncTeX, i.e. markers and preview.
+//!
+//! Each fragment goes into `\jl@box`, which is then handed to `\jl@put` along with the index of
+//! the fragment. The positioning defines `\jl@put`, which places the box and records its position
+//! and size.
+
+use indoc::indoc;
+
+/// The environment for inline fragments.
+pub const INLINE: &str = "jlbox";
+
+/// The environment for block fragments.
+pub const BLOCK: &str = "jlboxblock";
+
+/// Defines the [`INLINE`] and [`BLOCK`] environments. Goes right after the preamble, along with the
+/// definition of `\jl@put`.
+pub const SETUP: &str = indoc! {r"
+    \makeatletter
+    \newsavebox\jl@box
+    \newenvironment{jlbox}[1]{\def\jl@index{#1}\begin{lrbox}{\jl@box}}
+      {\end{lrbox}\jl@put\jl@index}
+    % Blocks go into a \vbox as wide as the text, without the skips around displays.
+    \newenvironment{jlboxblock}[1]{\def\jl@index{#1}\begin{lrbox}{\jl@box}\vbox\bgroup
+        \abovedisplayskip\z@ \belowdisplayskip\z@
+        \abovedisplayshortskip\z@ \belowdisplayshortskip\z@}
+      {\egroup\end{lrbox}\jl@put\jl@index}
+    \makeatother
+"};
+
+/// Wraps the expanded source of the fragment with the given index into the given environment,
+/// without adding lines before the source so that TeX errors can still be told apart by line.
+pub fn wrap(env: &str, index: usize, expanded: &str) -> String {
+    // The % keeps the line break out of inline boxes, and is harmless after a comment.
+    format!("\\begin{{{env}}}{{{index}}}{expanded}%\n\\end{{{env}}}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_keeps_lines() {
+        assert_eq!(
+            wrap(INLINE, 3, r"\(x\)"),
+            "\\begin{jlbox}{3}\\(x\\)%\n\\end{jlbox}"
+        );
+        let expanded = "\\[\n    x % comment\n\\]";
+        let wrapped = wrap(BLOCK, 0, expanded);
+        assert!(wrapped.starts_with(r"\begin{jlboxblock}{0}\["));
+        assert_eq!(wrapped.lines().count(), expanded.lines().count() + 1);
+    }
+}
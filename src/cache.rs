@@ -0,0 +1,66 @@
+//! Persistent on-disk cache of rendering results.
+//!
+//! Compiling fragments with LaTeX and converting the result with dvisvgm is by far the slowest part
+//! of the pipeline, and for a blog with hundreds of posts most documents do not change between two
+//! builds. The cache is content-addressed: entries are keyed by a hash of everything that affects
+//! the rendering result, so there is no need to invalidate anything. Stale entries are simply never
+//! looked up again and can be removed by emptying the cache directory.
+//!
+//! Keys are computed with [`std::collections::hash_map::DefaultHasher`], whose output is not
+//! guaranteed to be stable across Rust releases. That is fine here: the worst thing that can happen
+//! after a toolchain upgrade is a cache miss.
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use tempfile::NamedTempFile;
+
+pub struct RenderCache {
+    dir: PathBuf,
+}
+
+impl RenderCache {
+    /// Opens (and creates if necessary) a cache in the given directory.
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("creating cache directory {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    fn entry_path(&self, key: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.json", key))
+    }
+
+    /// Looks up an entry. Entries that cannot be read back (e.g. those written by an incompatible
+    /// version) are treated as misses.
+    pub fn load<T: DeserializeOwned>(&self, key: u64) -> Option<T> {
+        let file = File::open(self.entry_path(key)).ok()?;
+        match serde_json::from_reader(BufReader::new(file)) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                log::warn!("ignoring corrupt cache entry {:016x}: {}", key, e);
+                None
+            }
+        }
+    }
+
+    /// Stores an entry. The entry is written to a temporary file first and then moved in place, so
+    /// concurrent pandoc runs sharing a cache never observe half-written entries.
+    pub fn store<T: Serialize>(&self, key: u64, value: &T) -> Result<()> {
+        let mut temp = NamedTempFile::new_in(&self.dir)?;
+        {
+            let mut writer = BufWriter::new(temp.as_file_mut());
+            serde_json::to_writer(&mut writer, value)?;
+            writer.flush()?;
+        }
+        temp.persist(self.entry_path(key))
+            .context("writing cache entry")?;
+        Ok(())
+    }
+}
@@ -1,12 +1,12 @@
 use std::{env, path::Path};
 
 use anyhow::{bail, format_err, Context, Result};
-use config::{builder::DefaultState, ConfigBuilder};
+use config::builder::DefaultState;
 use indoc::indoc;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Config {
     pub preamble: String,
     pub postamble: String,
@@ -18,6 +18,12 @@ pub struct Config {
     // Should have really made this an enum. But writing manual impls for Deserialize does not seem
     // to worth the effort.
     pub mode: String,
+    /// How fragments are located on the page, either "synctex", "markers" or "preview".
+    ///
+    /// Under "markers", each fragment is typeset into a box whose position and size are recorded
+    /// with `\pdfsavepos`, see [`crate::markers`]. Under "preview", the `preview` package ships out
+    /// each fragment as its own tightly cropped page, see [`crate::preview`].
+    pub positioning: String,
     /// Defines the error tolerance for [`crate::x_range_for_y_range`] and
     /// [`crate::refine_y_range`].dvi_
     pub y_range_tol: f64,
@@ -32,6 +38,40 @@ pub struct Config {
     ///
     /// A positive value makes inline fragments higher.
     pub baseline_rise: f64,
+    /// How rendered fragments are delivered, either "script" or "static".
+    ///
+    /// With "script", all SVGs of a document are compressed and stuffed into a script that
+    /// decompresses them when the page loads. With "static", every fragment is cropped into its own
+    /// SVG which is embedded in the HTML directly, so the page works without JavaScript (e.g. in RSS
+    /// readers and email clients) at the cost of a larger HTML.
+    pub output: String,
+    /// How SVGs are embedded under static output, either "img" or "svg".
+    ///
+    /// With "img", each fragment becomes an <img> with a data URL. With "svg", each fragment
+    /// becomes an inline <svg>, which requires PDF mode.
+    pub static_embed: String,
+    /// Directory to write rendered SVGs to, instead of embedding them in the HTML.
+    ///
+    /// Files are named after a hash of their content, and referred to as
+    /// `<asset_url_prefix>/<hash>.svg`. Under script output, whole pages are written and no
+    /// decompressor script is needed. Under static output, each cropped fragment is written.
+    pub asset_dir: Option<String>,
+    /// URL under which the files in `asset_dir` are served, e.g. "/assets/jl".
+    pub asset_url_prefix: String,
+    /// How SVGs are compressed under script output, one of "lzma", "gzip", "deflate" or "none".
+    ///
+    /// LZMA gives the smallest HTML but needs a decoder library and a Web Worker. "gzip" and
+    /// "deflate" are decompressed natively by browsers supporting `DecompressionStream`.
+    pub compression: String,
+    /// Whether to also compress every page with each of the other codecs and print the size and
+    /// time of each, to help choose `compression`. Only the output of `compression` is used.
+    pub compare_compression: bool,
+    /// Where the decompressor script gets its LZMA decoder from, either "url" or "bundled".
+    ///
+    /// With "url", lzma-d-min.js is loaded from `lzma_js_path`. With "bundled", a decoder built
+    /// into just-latex is inlined into the script, so that the HTML is self-contained and works
+    /// offline and under strict CSPs.
+    pub lzma_js: String,
     /// Path to lzma-d-min.js.
     pub lzma_js_path: String,
     /// Extra attributes to the decompressor <script> tag.
@@ -51,6 +91,31 @@ pub struct Config {
     /// include extra styling in some separate CSS.
     pub extra_style_display: String,
 
+    /// Styles of the placeholders of fragments that failed to render, see `on_error`.
+    ///
+    /// Placeholders are also accessible via `.jl-error`.
+    pub error_style: String,
+    /// What to do when LaTeX fails to compile a fragment, either "fail" or "placeholder".
+    ///
+    /// With "fail", the whole document fails to convert. With "placeholder", the failing fragments
+    /// are left out and replaced by their source code, and the rest of the document is rendered as
+    /// usual.
+    pub on_error: String,
+    /// Whether to treat LaTeX warnings (overfull boxes, undefined references, missing characters,
+    /// etc.) as errors.
+    pub strict: bool,
+
+    /// Metadata fields whose maths are rendered, e.g. "title" or "abstract".
+    ///
+    /// If "title" is among them, `pagetitle` is set to the plain text of the title unless given,
+    /// because the <title> of the page cannot contain the rendered maths.
+    pub meta_keys: Vec<String>,
+    /// Commands that produce no output, without the backslash, e.g. "label" or "index".
+    ///
+    /// Raw TeX inlines made of nothing but these commands and their arguments are compiled, so
+    /// that labels and the like are defined, but not shown, like `%dontshow` blocks.
+    pub invisible_commands: Vec<String>,
+
     /// Configuration related to templating of fragments.
     pub template: TemplateConfig,
     /// Configuration for the SVG optimizer.
@@ -58,15 +123,25 @@ pub struct Config {
     /// Output folder for intermediate files. Useful in case of LaTeX compilation errors.
     /// If none, the program dumps everything in a temp folder.
     pub output_folder: Option<String>,
+    /// Directory of the persistent render cache. If set, documents whose preamble, templates and
+    /// fragments did not change since the last run are not compiled again.
+    pub cache_dir: Option<String>,
+    /// Granularity of the render cache, either "document" or "fragment".
+    ///
+    /// With "fragment", fragments are cached individually, so that editing one fragment only
+    /// requires that fragment to be compiled again.
+    pub cache_granularity: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TemplateConfig {
     /// The placeholder that will be replaced by the fragment content for all templates below.
     pub placeholder: String,
     /// Template for inline math
     pub inline_math: String,
     pub inline_math_inner: String,
+    /// Template for inline raw TeX, e.g. `\LaTeX` in the middle of a paragraph.
+    pub inline_raw: String,
     // Style elements
     pub strong: String,
     pub emph: String,
@@ -76,7 +151,7 @@ pub struct TemplateConfig {
     pub display_math: String,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct OptimizerConfig {
     /// Is the optimizer enabled?
     pub enabled: bool,
@@ -85,10 +160,134 @@ pub struct OptimizerConfig {
 }
 
 impl Config {
-    /// Loads configuration from config files, as well as document.
+    /// Loads configuration the way the filter does: from the defaults, the jlconfig.toml files and
+    /// the metadata of the document.
     pub fn load(tree: &Value) -> Result<Self> {
+        ConfigBuilder::new()?
+            .default_files()?
+            .document(tree)?
+            .build()
+    }
+
+    pub fn sanity_check(&self) -> Result<()> {
+        if self.mode != "pdf" && self.mode != "dvi" && self.mode != "xdv" {
+            bail!("unknown mode: must be one of 'pdf', 'dvi', or 'xdv'");
+        }
+        if self.mode != "pdf" && self.optimizer.enabled {
+            bail!("DVI/XDV mode is incompatible with JustLaTeX's SVG optimizer");
+        }
+        if !["synctex", "markers", "preview"].contains(&self.positioning.as_str()) {
+            bail!("unknown positioning: must be one of 'synctex', 'markers' or 'preview'");
+        }
+        if self.positioning == "preview" && self.mode != "pdf" {
+            // The page sizes set by the preview package only reach dvisvgm through PDF files.
+            bail!("preview positioning requires PDF mode");
+        }
+        if self.output != "script" && self.output != "static" {
+            bail!("unknown output: must be one of 'script' or 'static'");
+        }
+        if self.static_embed != "img" && self.static_embed != "svg" {
+            bail!("unknown static_embed: must be one of 'img' or 'svg'");
+        }
+        if self.output == "static" && self.static_embed == "svg" && self.mode != "pdf" {
+            // The fonts dvisvgm embeds under DVI/XDV mode come with global CSS rules, which clash
+            // once multiple SVGs are inlined into the same HTML.
+            bail!("inline SVGs under static output require PDF mode");
+        }
+        if self.asset_dir.is_some() && self.output == "static" && self.static_embed == "svg" {
+            bail!("asset_dir cannot be used with inline SVGs");
+        }
+        if !["lzma", "gzip", "deflate", "none"].contains(&self.compression.as_str()) {
+            bail!("unknown compression: must be one of 'lzma', 'gzip', 'deflate', or 'none'");
+        }
+        if self.lzma_js != "url" && self.lzma_js != "bundled" {
+            bail!("unknown lzma_js: must be one of 'url' or 'bundled'");
+        }
+        if self.on_error != "fail" && self.on_error != "placeholder" {
+            bail!("unknown on_error: must be one of 'fail' or 'placeholder'");
+        }
+        if self.cache_granularity != "document" && self.cache_granularity != "fragment" {
+            bail!("unknown cache granularity: must be one of 'document' or 'fragment'");
+        }
+        Ok(())
+    }
+}
+
+/// The value of a single option given to [`ConfigBuilder::set`], e.g. `"pdf"`, `true`, `0.001` or
+/// a list of strings.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OptionValue {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    List(Vec<OptionValue>),
+}
+
+impl From<bool> for OptionValue {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<i64> for OptionValue {
+    fn from(value: i64) -> Self {
+        Self::Integer(value)
+    }
+}
+
+impl From<f64> for OptionValue {
+    fn from(value: f64) -> Self {
+        Self::Float(value)
+    }
+}
+
+impl From<&str> for OptionValue {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
+impl From<String> for OptionValue {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl<T: Into<OptionValue>> From<Vec<T>> for OptionValue {
+    fn from(values: Vec<T>) -> Self {
+        Self::List(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<OptionValue> for config::ValueKind {
+    fn from(value: OptionValue) -> Self {
+        match value {
+            OptionValue::Bool(value) => Self::Boolean(value),
+            OptionValue::Integer(value) => Self::I64(value),
+            OptionValue::Float(value) => Self::Float(value),
+            OptionValue::String(value) => Self::String(value),
+            OptionValue::List(values) => {
+                Self::Array(values.into_iter().map(config::Value::from).collect())
+            }
+        }
+    }
+}
+
+/// Builds a [`Config`] from the defaults, configuration files, individual options and the metadata
+/// of documents.
+///
+/// Files added later take precedence over those added earlier. Options given through [`Self::set`]
+/// and [`Self::document`] take precedence over all files.
+pub struct ConfigBuilder {
+    builder: config::ConfigBuilder<DefaultState>,
+}
+
+impl ConfigBuilder {
+    /// Starts from the default configuration.
+    pub fn new() -> Result<Self> {
         let placeholder = "{{fragment}}";
-        let mut c = config::Config::builder()
+        let builder = config::Config::builder()
             .set_default(
                 "preamble",
                 indoc! {r"
@@ -103,19 +302,63 @@ impl Config {
             .set_default("latex", "pdflatex")?
             .set_default("dvisvgm", "dvisvgm")?
             .set_default("mode", "pdf")?
+            .set_default("positioning", "synctex")?
             .set_default("y_range_tol", 0.0)?
             .set_default("x_range_margin", 1.0)?
             .set_default("y_range_margin", 1.0)?
             .set_default("baseline_rise", 0.0)?
+            .set_default("output", "script")?
+            .set_default("static_embed", "img")?
+            .set_default("asset_dir", Option::<String>::None)?
+            .set_default("asset_url_prefix", "")?
+            .set_default("compression", "lzma")?
+            .set_default("compare_compression", false)?
+            .set_default("lzma_js", "url")?
             .set_default("lzma_js_path", "https://cdn.jsdelivr.net/npm/lzma@2/src/lzma-d-min.js")?
             .set_default("script_extra_attributes", "")?
             .set_default("extra_style_inline", "")?
             .set_default("extra_style_display", "")?
+            .set_default(
+                "error_style",
+                "color:#b00020;border:1px dashed #b00020;white-space:pre-wrap;",
+            )?
+            .set_default("on_error", "fail")?
+            .set_default("strict", false)?
             .set_default("output_folder", Option::<String>::None)?
+            .set_default("cache_dir", Option::<String>::None)?
+            .set_default("cache_granularity", "document")?
+            .set_default("meta_keys", vec!["title", "subtitle", "abstract"])?
+            .set_default(
+                "invisible_commands",
+                vec![
+                    "label",
+                    "index",
+                    "glossary",
+                    "nocite",
+                    "phantomsection",
+                    "addcontentsline",
+                    "noindent",
+                    "indent",
+                    "hfill",
+                    "vfill",
+                    "vspace",
+                    "smallskip",
+                    "medskip",
+                    "bigskip",
+                    "linebreak",
+                    "nolinebreak",
+                    "newline",
+                    "pagebreak",
+                    "nopagebreak",
+                    "newpage",
+                    "clearpage",
+                ],
+            )?
             // Default templates...
             .set_default("template.placeholder", placeholder)?
             .set_default("template.inline_math", format!(r"\({}\)", placeholder))?
             .set_default("template.inline_math_inner", placeholder)?
+            .set_default("template.inline_raw", placeholder)?
             .set_default("template.inline_quote", placeholder)?
             .set_default("template.emph", placeholder)?
             .set_default("template.strong", placeholder)?
@@ -128,21 +371,43 @@ impl Config {
             .set_default("template.display_math", format!("\\[\n    {}\n\\]", placeholder))?
             .set_default("optimizer.enabled", false)?
             .set_default("optimizer.eps", 0.001)?;
+        Ok(Self { builder })
+    }
+
+    /// Adds a TOML configuration file.
+    pub fn file(mut self, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        self.builder = self.builder.add_source(config::File::new(
+            path.to_str().context("cannot convert path to string")?,
+            config::FileFormat::Toml,
+        ));
+        Ok(self)
+    }
 
+    /// Adds the configuration files the filter reads, i.e. jlconfig.toml next to the executable
+    /// and in the current directory, if they exist.
+    pub fn default_files(mut self) -> Result<Self> {
         let exe_config = env::current_exe()?.join("jlconfig.toml");
         if exe_config.exists() {
-            c = c.add_source(config::File::new(
-                exe_config
-                    .to_str()
-                    .context("cannot convert path to string")?,
-                config::FileFormat::Toml,
-            ));
+            self = self.file(exe_config)?;
         }
-
         if Path::new("jlconfig.toml").exists() {
-            c = c.add_source(config::File::new("jlconfig.toml", config::FileFormat::Toml));
+            self = self.file("jlconfig.toml")?;
         }
+        Ok(self)
+    }
 
+    /// Sets a single option, e.g. `set("template.inline_math", r"\(\displaystyle {{fragment}}\)")`.
+    pub fn set(mut self, key: &str, value: impl Into<OptionValue>) -> Result<Self> {
+        self.builder = self.builder.set_override(key, value.into())?;
+        Ok(self)
+    }
+
+    /// Applies the options given in the metadata of a document (Pandoc JSON AST), i.e. the
+    /// `jlconfig` map and `jlconfig.<option>` fields.
+    pub fn document(mut self, tree: &Value) -> Result<Self> {
+        // The layout of the metadata depends on the version of the AST.
+        crate::ApiVersion::read(tree)?;
         for (key, value) in tree["meta"]
             .as_object()
             .context("reading document metadata")?
@@ -152,34 +417,32 @@ impl Config {
                     bail!("in Front Matter configuration, jlconfig must be a map!");
                 }
                 for (sub_key, value) in value["c"].as_object().context("reading map of MetaMap")? {
-                    c = walk_meta(c, value, sub_key)?;
+                    self.builder = walk_meta(self.builder, value, sub_key)?;
                 }
             } else if let Some(key) = key.strip_prefix("jlconfig.") {
-                c = walk_meta(c, value, key)?;
+                self.builder = walk_meta(self.builder, value, key)?;
             }
         }
-
-        c.build()?
-            .try_deserialize()
-            .map_err(|e| format_err!("cannot load config: {}", e))
+        Ok(self)
     }
 
-    pub fn sanity_check(&self) -> Result<()> {
-        if self.mode != "pdf" && self.mode != "dvi" && self.mode != "xdv" {
-            bail!("unknown mode: must be one of 'pdf', 'dvi', or 'xdv'");
-        }
-        if self.mode != "pdf" && self.optimizer.enabled {
-            bail!("DVI/XDV mode is incompatible with JustLaTeX's SVG optimizer");
-        }
-        Ok(())
+    /// Builds and checks the configuration.
+    pub fn build(self) -> Result<Config> {
+        let config: Config = self
+            .builder
+            .build()?
+            .try_deserialize()
+            .map_err(|e| format_err!("cannot load config: {}", e))?;
+        config.sanity_check()?;
+        Ok(config)
     }
 }
 
 fn walk_meta(
-    mut cb: ConfigBuilder<DefaultState>,
+    mut cb: config::ConfigBuilder<DefaultState>,
     value: &Value,
     key: &str,
-) -> Result<ConfigBuilder<DefaultState>> {
+) -> Result<config::ConfigBuilder<DefaultState>> {
     match value["t"].as_str().context("reading type of Meta")? {
         "MetaInlines" => {
             let mut content = String::new();
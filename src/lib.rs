@@ -0,0 +1,2140 @@
+//! Renders the LaTeX fragments of a Pandoc document with an actual LaTeX engine.
+//!
+//! This is the library behind the just-latex Pandoc filter, for programs that would rather call
+//! the renderer in-process than spawn the filter:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! let mut tree: serde_json::Value = serde_json::from_str(&std::fs::read_to_string("doc.json")?)?;
+//! let config = just_latex::ConfigBuilder::new()?
+//!     .set("mode", "pdf")?
+//!     .document(&tree)?
+//!     .build()?;
+//! let report = just_latex::render(&mut tree, &config)?;
+//! eprintln!("rendered {} fragments", report.fragments);
+//! # Ok(())
+//! # }
+//! ```
+
+use anyhow::{bail, Context, Result};
+use bytesize::ByteSize;
+use flate2::{
+    read::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use indoc::formatdoc;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    fmt,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io::{Cursor, Read, Write},
+    ops::Range,
+    path::Path,
+    process::Command,
+    rc::Rc,
+    time::Instant,
+    vec,
+};
+use tempfile::TempDir;
+use xz2::{read::XzEncoder, stream::LzmaOptions};
+
+use crate::assets::AssetDir;
+use crate::ast::{Block, Inline, MathType, MetaValue, MutVisitor, Pandoc};
+use crate::cache::RenderCache;
+pub use crate::config::{Config, ConfigBuilder, OptimizerConfig, OptionValue, TemplateConfig};
+use crate::synctex::Scanner;
+use crate::tex_log::TeXError;
+
+mod assets;
+mod ast;
+mod boxes;
+mod cache;
+mod config;
+mod markers;
+mod preview;
+mod svg_optimize;
+mod svg_utils;
+mod synctex;
+mod tex_log;
+
+/// Summary of a [`render`] call.
+#[derive(Clone, Debug, Default)]
+pub struct RenderReport {
+    /// Number of distinct fragments found in the document, including `%dontshow` ones.
+    pub fragments: usize,
+    /// Number of pages, i.e. SVGs, the fragments were rendered to.
+    pub pages: usize,
+    /// Fragments that failed to render under `on_error = "placeholder"`, as pairs of the source of
+    /// the fragment and the error message.
+    pub failed: Vec<(String, String)>,
+    /// Warnings from the LaTeX log, each prefixed by where it comes from.
+    pub warnings: Vec<String>,
+}
+
+/// Renders all LaTeX fragments of a document given as Pandoc JSON AST, replacing them in-place with
+/// SVGs for HTML output. This is what the filter does when Pandoc produces anything but LaTeX.
+pub fn render(tree: &mut Value, config: &Config) -> Result<RenderReport> {
+    ApiVersion::read(tree)?;
+    config.sanity_check()?;
+    let mut pandoc: Pandoc = serde_json::from_value(tree.take())?;
+    let report = FragmentRenderer::new(config.clone()).render_with_latex(&mut pandoc);
+    // Put the tree back even if rendering failed, so that the caller is not left with null.
+    *tree = serde_json::to_value(&pandoc)?;
+    report
+}
+
+/// Kind of a fragment given to [`render_fragments`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FragmentKind {
+    /// Inline maths, expanded with `template.inline_math`.
+    InlineMath,
+    /// Display maths, expanded with `template.display_math`.
+    DisplayMath,
+    /// Raw TeX, rendered as a block.
+    RawBlock,
+    /// Raw TeX, rendered inline and expanded with `template.inline_raw`.
+    RawInline,
+    /// Raw TeX that is compiled but not shown, e.g. macro definitions for later fragments.
+    DontShow,
+}
+
+/// A fragment rendered by [`render_fragments`].
+#[derive(Clone, Debug)]
+pub struct RenderedFragment {
+    /// The fragment as standalone SVGs. This is usually a single one, but display fragments broken
+    /// across pages by TeX come in multiple parts, and `DontShow` fragments have none.
+    pub parts: Vec<SvgPart>,
+    /// The error message, if the fragment failed to render under `on_error = "placeholder"`.
+    pub error: Option<String>,
+}
+
+/// A standalone SVG of (a part of) a rendered fragment. All lengths are in pt.
+#[derive(Clone, Debug)]
+pub struct SvgPart {
+    pub svg: String,
+    pub width: f64,
+    pub height: f64,
+    /// How far the SVG extends below the baseline, for aligning inline fragments with the
+    /// surrounding text. Always zero for block fragments.
+    pub depth: f64,
+}
+
+/// Renders a list of TeX fragments into standalone SVGs, independent of Pandoc. The fragments are
+/// compiled together in one document, in order, so later fragments see the definitions of earlier
+/// `DontShow` ones. The result has one entry for each given fragment.
+pub fn render_fragments(
+    fragments: &[(FragmentKind, &str)],
+    config: &Config,
+) -> Result<Vec<RenderedFragment>> {
+    config.sanity_check()?;
+    let mut renderer = FragmentRenderer::new(config.clone());
+    // Not going through add_fragment, which merges duplicate inline fragments.
+    renderer.fragments = fragments
+        .iter()
+        .map(|(kind, src)| Fragment {
+            ty: match kind {
+                FragmentKind::InlineMath => FragmentType::InlineMath(Style::Plain),
+                FragmentKind::DisplayMath => FragmentType::DisplayMath,
+                FragmentKind::RawBlock => FragmentType::RawBlock,
+                FragmentKind::RawInline => FragmentType::RawInline,
+                FragmentKind::DontShow => FragmentType::DontShow,
+            },
+            src: src.trim().into(),
+            refs: vec![],
+        })
+        .collect();
+    renderer.render_standalone()
+}
+
+/// Prepares a document given as Pandoc JSON AST for LaTeX output. Maths are left for Pandoc, while
+/// raw fragments become raw TeX. This is what the filter does when Pandoc produces LaTeX or PDF.
+pub fn prepare_for_latex(tree: &mut Value, config: &Config) -> Result<()> {
+    ApiVersion::read(tree)?;
+    config.sanity_check()?;
+    let mut pandoc: Pandoc = serde_json::from_value(tree.take())?;
+    let result = FragmentRenderer::new(config.clone()).pass_through_to_latex(&mut pandoc);
+    *tree = serde_json::to_value(&pandoc)?;
+    result
+}
+
+/// Extracts the `\usepackage` lines from a preamble, except for geometry, which is there only to
+/// shape the pages of our own .tex. Geometry is dropped from lists of packages as well.
+fn usepackage_lines(preamble: &str) -> String {
+    let usepackage = Regex::new(r"^\s*\\usepackage\s*(\[[^\]]*\])?\s*\{([^}]*)\}").unwrap();
+    preamble
+        .lines()
+        .filter_map(|line| {
+            let capture = usepackage.captures(line)?;
+            let packages = capture[2]
+                .split(',')
+                .map(str::trim)
+                .filter(|package| !package.is_empty() && *package != "geometry")
+                .collect::<Vec<_>>();
+            if packages.is_empty() {
+                return None;
+            }
+            Some(format!(
+                "\\usepackage{}{{{}}}{}",
+                capture.get(1).map_or("", |options| options.as_str()),
+                packages.join(", "),
+                &line[capture.get(0).unwrap().end()..]
+            ))
+        })
+        .map(|line| line.trim().to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Source of the LZMA decoder used under `lzma_js = "bundled"`.
+const LZMA_DECODER_JS: &str = include_str!("lzma_decoder.js");
+
+/// Strips indentation and comment lines from JS source, which is good enough for the hand-written
+/// scripts we embed.
+fn minify_js(src: &str) -> String {
+    src.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("//"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Whether raw TeX consists of nothing but the given commands (without the backslash) along with
+/// their optional and mandatory arguments.
+fn is_invisible(src: &str, commands: &[String]) -> bool {
+    let mut rest = src.trim();
+    if rest.is_empty() {
+        return false;
+    }
+    while !rest.is_empty() {
+        let name = match rest.strip_prefix('\\') {
+            Some(name) => {
+                &name[..name
+                    .find(|c: char| !c.is_ascii_alphabetic())
+                    .unwrap_or(name.len())]
+            }
+            None => return false,
+        };
+        if !commands.iter().any(|command| command == name) {
+            return false;
+        }
+        rest = &rest[1 + name.len()..];
+        rest = rest.strip_prefix('*').unwrap_or(rest);
+        loop {
+            rest = rest.trim_start();
+            let (open, close) = match rest.as_bytes().first() {
+                Some(b'{') => (b'{', b'}'),
+                Some(b'[') => (b'[', b']'),
+                _ => break,
+            };
+            let mut depth = 0;
+            let end = rest.bytes().position(|b| {
+                if b == open {
+                    depth += 1;
+                } else if b == close {
+                    depth -= 1;
+                }
+                depth == 0
+            });
+            match end {
+                Some(end) => rest = &rest[end + 1..],
+                None => return false,
+            }
+        }
+    }
+    true
+}
+
+/// Compresses an SVG with the given codec (see [`Config::compression`]) into the format the
+/// decompressor script expects.
+fn compress(codec: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let mut compressed = vec![];
+    match codec {
+        "lzma" => {
+            let options = LzmaOptions::new_preset(9)?;
+            let mut encoder = XzEncoder::new_stream(
+                Cursor::new(data),
+                xz2::stream::Stream::new_lzma_encoder(&options)?,
+            );
+            encoder.read_to_end(&mut compressed)?;
+        }
+        "gzip" => {
+            let mut encoder = GzEncoder::new(Cursor::new(data), Compression::best());
+            encoder.read_to_end(&mut compressed)?;
+        }
+        // What DecompressionStream calls "deflate" is actually the zlib format.
+        "deflate" => {
+            let mut encoder = ZlibEncoder::new(Cursor::new(data), Compression::best());
+            encoder.read_to_end(&mut compressed)?;
+        }
+        "none" => compressed.extend_from_slice(data),
+        _ => bail!("unknown compression: {}", codec),
+    }
+    Ok(compressed)
+}
+
+/// Version of the Pandoc JSON AST, i.e. the version of pandoc-types, without the patch level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct ApiVersion(u64, u64);
+
+impl ApiVersion {
+    /// The oldest version we can walk, which is the first one that has `pandoc-api-version` in the
+    /// JSON (Pandoc 1.18).
+    const OLDEST: Self = Self(1, 17);
+
+    /// Reads the version from the `pandoc-api-version` field of a document.
+    pub(crate) fn read(tree: &Value) -> Result<Self> {
+        let version = tree["pandoc-api-version"]
+            .as_array()
+            .context("missing pandoc-api-version in the document: is Pandoc older than 1.18?")?
+            .iter()
+            .map(|v| v.as_u64().context("reading pandoc-api-version"))
+            .collect::<Result<Vec<_>>>()?;
+        let version = match version[..] {
+            [major, minor, ..] => Self(major, minor),
+            _ => bail!("malformed pandoc-api-version: {:?}", version),
+        };
+        if version.0 != 1 {
+            bail!(
+                "unsupported pandoc-api-version {}.{}: only 1.x is supported, \
+                 please check for a newer version of just-latex",
+                version.0,
+                version.1
+            );
+        }
+        if version < Self::OLDEST {
+            bail!(
+                "unsupported pandoc-api-version {}.{}: please upgrade Pandoc",
+                version.0,
+                version.1
+            );
+        }
+        Ok(version)
+    }
+}
+
+#[derive(Debug)]
+struct FragmentRenderer<'a> {
+    config: Config,
+    fragments: Vec<Fragment<'a>>,
+    /// Style of the inline maths currently being visited.
+    style: Style,
+}
+
+#[derive(Debug)]
+struct Fragment<'a> {
+    ty: FragmentType,
+    src: String,
+    refs: Vec<FragmentNodeRef<'a>>,
+}
+
+#[derive(Debug)]
+enum FragmentNodeRef<'a> {
+    Inline(&'a mut Inline),
+    Block(&'a mut Block),
+}
+
+#[derive(Debug, PartialEq)]
+enum FragmentType {
+    /// For ordinary inline maths.
+    InlineMath(Style),
+    /// For inline raw TeX, e.g. `\LaTeX`. Rendered inline, like inline maths.
+    RawInline,
+    /// For display maths.
+    DisplayMath,
+    /// These will be included in the .tex file without being surrounded by "{}".
+    RawBlock,
+    /// For display maths starting with %dontshow. They are included in the tex files but not shown.
+    /// Use them for macro definitions.
+    DontShow,
+}
+
+/// The result of compiling all fragments of a document: the SVG pages produced by dvisvgm, and for
+/// each fragment (in order) the regions on these pages it occupies. This is also what gets stored in
+/// the render cache.
+#[derive(Debug, Serialize, Deserialize)]
+struct Rendered {
+    pages: Vec<String>,
+    regions: Vec<Vec<Region>>,
+    /// Fragments that failed to compile under `on_error = "placeholder"`, along with the errors.
+    /// Their regions are empty.
+    #[serde(default)]
+    failed: BTreeMap<usize, String>,
+    /// Warnings from the LaTeX log, along with where they come from.
+    #[serde(default)]
+    warnings: Vec<String>,
+}
+
+/// A rectangular region on a page, in SVG coordinates.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Region {
+    /// Index of the page, starting from 0.
+    page: usize,
+    x_range: (f64, f64),
+    y_range: (f64, f64),
+    /// Distance from the baseline to the bottom of the region. Always 0 for block fragments.
+    depth: f64,
+}
+
+/// LaTeX failed to compile the document. Errors in the log are attributed to fragments using the
+/// line mappings of the generated source.
+#[derive(Debug)]
+struct LaTeXError {
+    errors: Vec<LocatedTeXError>,
+    /// Descriptions of the compiled fragments, for display.
+    fragments: Vec<String>,
+}
+
+#[derive(Debug)]
+struct LocatedTeXError {
+    location: Location,
+    details: String,
+}
+
+/// Where a line of the generated LaTeX source comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Location {
+    /// The preamble, with the line number.
+    Preamble(usize),
+    /// A fragment, as an index into the compiled fragments.
+    Fragment(usize),
+    /// The postamble, with the line number.
+    Postamble(usize),
+    Unknown,
+}
+
+impl Location {
+    /// Describes the location for diagnostics, given the descriptions of the compiled fragments.
+    fn describe(&self, fragments: &[String]) -> String {
+        match self {
+            Location::Preamble(line) => format!("the preamble (line {})", line),
+            Location::Fragment(idx) => fragments[*idx].clone(),
+            Location::Postamble(line) => format!("the postamble (line {})", line),
+            Location::Unknown => "unknown location".into(),
+        }
+    }
+}
+
+/// Finds where a line of the generated LaTeX source comes from.
+fn locate_line(lines: &[Range<usize>], line: Option<usize>) -> Location {
+    let line = match line {
+        Some(line) => line,
+        None => return Location::Unknown,
+    };
+    // TeX frequently notices a problem only when it reads the next line, e.g. a missing closing
+    // brace that is detected at the end of the paragraph. So the blank line that follows a
+    // fragment is attributed to that fragment as well.
+    match lines
+        .iter()
+        .position(|range| range.start <= line && line <= range.end)
+    {
+        Some(idx) => Location::Fragment(idx),
+        None if lines.first().is_none_or(|range| line < range.start) => Location::Preamble(line),
+        None => Location::Postamble(line),
+    }
+}
+
+impl LaTeXError {
+    fn new(fragments: &[&Fragment], lines: &[Range<usize>], errors: Vec<TeXError>) -> Self {
+        let errors = errors
+            .into_iter()
+            .map(|error| {
+                let location = locate_line(lines, error.line);
+                let mut details = format!("! {}", error.message);
+                for context in error.context {
+                    details.push('\n');
+                    details.push_str(&context);
+                }
+                LocatedTeXError { location, details }
+            })
+            .collect();
+        let fragments = fragments.iter().map(|item| item.describe()).collect();
+        Self { errors, fragments }
+    }
+
+    /// Returns whether any error is located in the preamble, which no fragment can be blamed for.
+    fn in_preamble(&self) -> bool {
+        self.errors
+            .iter()
+            .any(|error| matches!(error.location, Location::Preamble(_)))
+    }
+
+    /// Returns the fragments that caused errors along with the error details.
+    fn failed_fragments(&self) -> BTreeMap<usize, String> {
+        let mut failed = BTreeMap::<usize, String>::new();
+        for error in self.errors.iter() {
+            if let Location::Fragment(idx) = error.location {
+                let details = failed.entry(idx).or_default();
+                if !details.is_empty() {
+                    details.push('\n');
+                }
+                details.push_str(&error.details);
+            }
+        }
+        failed
+    }
+}
+
+impl fmt::Display for LaTeXError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "latex reported {} error(s):", self.errors.len())?;
+        for error in self.errors.iter() {
+            write!(f, "\nin {}:", error.location.describe(&self.fragments))?;
+            for line in error.details.lines() {
+                write!(f, "\n    {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LaTeXError {}
+
+/// An entry of the fragment-level render cache.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedFragment {
+    /// Keys of the pages the fragment is on. [`Region::page`] indexes into this.
+    pages: Vec<u64>,
+    regions: Vec<Region>,
+}
+
+/// Maximum number of distinct cached pages a document may be stitched together from under
+/// fragment-level caching, before the whole document is compiled again.
+const MAX_CACHED_PAGES: usize = 8;
+
+impl<'a> Fragment<'a> {
+    /// Describes the fragment for diagnostics.
+    fn describe(&self) -> String {
+        format!("{} fragment `{}`", self.ty.name(), self.src)
+    }
+
+    /// Replaces all nodes referring to this fragment with raw HTML.
+    fn replace_with_html(&mut self, html: &str) {
+        self.replace_with_html_each(|_| html.into());
+    }
+
+    /// Replaces the nodes referring to this fragment with raw HTML, which may differ between the
+    /// nodes. `html` is given the index of the node.
+    fn replace_with_html_each(&mut self, html: impl Fn(usize) -> String) {
+        for (i, node) in self.refs.iter_mut().enumerate() {
+            match node {
+                FragmentNodeRef::Inline(node) => **node = Inline::RawInline("html".into(), html(i)),
+                FragmentNodeRef::Block(node) => **node = Block::RawBlock("html".into(), html(i)),
+            }
+        }
+    }
+}
+
+impl FragmentType {
+    /// Human-readable name of the fragment type, used in diagnostics.
+    fn name(&self) -> &'static str {
+        match self {
+            FragmentType::InlineMath(_) => "inline math",
+            FragmentType::RawInline => "raw inline",
+            FragmentType::DisplayMath => "display math",
+            FragmentType::RawBlock => "raw block",
+            FragmentType::DontShow => "%dontshow",
+        }
+    }
+
+    /// Whether the fragment is rendered inline, aligned to the baseline of the surrounding text.
+    fn is_inline(&self) -> bool {
+        matches!(self, FragmentType::InlineMath(_) | FragmentType::RawInline)
+    }
+}
+
+// On style: technically the correct way to handle styles is to handle find a set or orthogonal
+// properties and make a product type out of it. But this is not extensible in a sense that
+// orthogonality might be broken as new styles are considered. So instead we here just consider
+// style to be an ordered list of style elements. The problem with this approach, however, is that
+// it becomes difficult to compare equivalence of styles. Is Strong then Emph equivalent to Emph
+// then Strong? Is nested Quote equivalent to single Quote? Equivalence of styles is necessary to
+// deduplicate fragments and reduce size of our output. Of course for sane inputs this wouldn't be
+// a problem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StyleElement {
+    Header(u64),
+    Quote,
+    Strong,
+    Emph,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Inline math style.
+enum Style {
+    Plain,
+    Fancy { base: Rc<Style>, this: StyleElement },
+}
+
+impl Style {
+    fn push(self, new: StyleElement) -> Self {
+        Self::Fancy {
+            base: Rc::new(self),
+            this: new,
+        }
+    }
+
+    fn template(&self, config: &TemplateConfig) -> String {
+        match self {
+            Style::Plain => config.inline_math_inner.clone(),
+            Style::Fancy { base, this } => {
+                let base_template = base.template(config);
+                let this_template = match this {
+                    StyleElement::Header(level) => &config.header[*level as usize - 1],
+                    StyleElement::Quote => &config.quote,
+                    StyleElement::Strong => &config.strong,
+                    StyleElement::Emph => &config.emph,
+                };
+                this_template.replace(&config.placeholder, &base_template)
+            }
+        }
+    }
+}
+
+impl<'a> FragmentRenderer<'a> {
+    fn new(config: Config) -> Self {
+        Self {
+            config,
+            fragments: vec![],
+            style: Style::Plain,
+        }
+    }
+
+    fn add_fragment(&mut self, ty: FragmentType, src: &str, node_ref: FragmentNodeRef<'a>) {
+        match ty {
+            // Inline fragments are often duplicates of previous ones encountered.
+            // Caveat: if inline fragments contain expansions of macro with side effect (which is
+            // rather unlikely), then this could cause trouble!
+            FragmentType::InlineMath(_) | FragmentType::RawInline => {
+                let src = src.trim();
+                for item in self.fragments.iter_mut() {
+                    if item.src == src && item.ty == ty {
+                        item.refs.push(node_ref);
+                        return;
+                    }
+                }
+                self.fragments.push(Fragment {
+                    ty,
+                    src: src.into(),
+                    refs: vec![node_ref],
+                });
+            }
+            _ => {
+                self.fragments.push(Fragment {
+                    ty,
+                    src: src.trim().into(),
+                    refs: vec![node_ref],
+                });
+            }
+        }
+    }
+
+    /// Expands a fragment into the LaTeX code that will appear in the .tex file.
+    fn expand(&self, item: &Fragment) -> String {
+        let template_config = &self.config.template;
+        match &item.ty {
+            FragmentType::InlineMath(style) => {
+                let inner = style
+                    .template(template_config)
+                    .replace(&template_config.placeholder, &item.src);
+                self.config
+                    .template
+                    .inline_math
+                    .replace(&template_config.placeholder, &inner)
+            }
+            FragmentType::RawInline => template_config
+                .inline_raw
+                .replace(&template_config.placeholder, &item.src),
+            FragmentType::DisplayMath => template_config
+                .display_math
+                .replace(&template_config.placeholder, &item.src),
+            FragmentType::RawBlock | FragmentType::DontShow => item.src.clone(),
+        }
+    }
+
+    fn generate_latex_with_line_mappings(
+        &self,
+        fragments: &[&Fragment],
+    ) -> (String, Vec<Range<usize>>) {
+        let mut lines: Vec<Range<usize>> = vec![];
+        let mut output = String::new();
+        let use_markers = self.config.positioning == "markers";
+        let use_preview = self.config.positioning == "preview";
+        let preamble = if use_preview {
+            preview::add_package(&self.config.preamble)
+        } else {
+            self.config.preamble.clone()
+        };
+        let preamble_trimmed = preamble.trim_end();
+        output.push_str(preamble_trimmed);
+        output.push('\n');
+        let mut current_line = preamble_trimmed.lines().count() + 1;
+        let setup = if use_markers {
+            format!("{}{}", boxes::SETUP, markers::SETUP)
+        } else if use_preview {
+            format!("{}{}", boxes::SETUP, preview::SETUP)
+        } else {
+            String::new()
+        };
+        if !setup.is_empty() {
+            output.push_str(&setup);
+            output.push('\n');
+            current_line += setup.lines().count();
+        }
+        for (i, item) in fragments.iter().enumerate() {
+            let expanded = self.expand(item);
+            let expanded = match item.ty {
+                FragmentType::DontShow => expanded,
+                _ if use_markers || use_preview => {
+                    let env = if item.ty.is_inline() {
+                        boxes::INLINE
+                    } else {
+                        boxes::BLOCK
+                    };
+                    boxes::wrap(env, i, expanded.trim_end())
+                }
+                _ => expanded,
+            };
+            let expanded = expanded.trim_end();
+            let start_line = current_line;
+            output.push_str(expanded);
+            current_line += expanded.lines().count();
+            lines.push(start_line..current_line);
+            output.push_str("\n\n");
+            current_line += 1;
+        }
+        output.push_str(&self.config.postamble);
+        (output, lines)
+    }
+
+    /// Hashes the toolchain, the mode and the region computation parameters, i.e. everything
+    /// besides the LaTeX source that affects the SVG pages and the fragment regions.
+    fn hash_render_settings(&self, hasher: &mut DefaultHasher) {
+        env!("CARGO_PKG_VERSION").hash(hasher);
+        self.config.latex.hash(hasher);
+        self.config.dvisvgm.hash(hasher);
+        self.config.mode.hash(hasher);
+        self.config.positioning.hash(hasher);
+        self.config.on_error.hash(hasher);
+        self.config.strict.hash(hasher);
+        for param in [
+            self.config.y_range_tol,
+            self.config.x_range_margin,
+            self.config.y_range_margin,
+        ] {
+            param.to_bits().hash(hasher);
+        }
+    }
+
+    /// Computes the key of the current document in the render cache. The key covers the generated
+    /// LaTeX source (i.e. the preamble, the postamble, the templates and the ordered fragment list)
+    /// and the render settings.
+    fn cache_key(&self) -> u64 {
+        let fragments = self.fragments.iter().collect::<Vec<_>>();
+        let (source, _) = self.generate_latex_with_line_mappings(&fragments);
+        let mut hasher = DefaultHasher::new();
+        "document".hash(&mut hasher);
+        self.hash_render_settings(&mut hasher);
+        source.hash(&mut hasher);
+        for item in self.fragments.iter() {
+            std::mem::discriminant(&item.ty).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Computes the key of a single fragment in the fragment-level render cache.
+    ///
+    /// Besides the expanded fragment itself, the key covers the preamble, the postamble and all
+    /// %dontshow fragments of the document, since these are exactly what gets compiled along with the
+    /// fragment. Side effects of other visible fragments (counters, macros defined in raw blocks)
+    /// are not tracked.
+    fn fragment_cache_key(&self, item: &Fragment) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        "fragment".hash(&mut hasher);
+        self.hash_render_settings(&mut hasher);
+        self.config.preamble.trim_end().hash(&mut hasher);
+        self.config.postamble.hash(&mut hasher);
+        for context in self.fragments.iter() {
+            if let FragmentType::DontShow = context.ty {
+                context.src.hash(&mut hasher);
+            }
+        }
+        std::mem::discriminant(&item.ty).hash(&mut hasher);
+        self.expand(item).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Renders the document using the fragment-level cache. Only fragments that are not found in
+    /// the cache are compiled, together with all %dontshow fragments of the document.
+    ///
+    /// Cached fragments point to the SVG pages they have been compiled to, which are stored in the
+    /// cache as well. The rendering result is then stitched together from these pages and the ones
+    /// freshly compiled.
+    fn render_incrementally(&self, cache: &RenderCache) -> Result<Rendered> {
+        // Pages referenced by cached fragments, by page key.
+        let mut cached_pages: HashMap<u64, String> = HashMap::new();
+        let mut entries = self
+            .fragments
+            .iter()
+            .map(|item| -> Option<CachedFragment> {
+                if let FragmentType::DontShow = item.ty {
+                    return None;
+                }
+                let entry = cache.load::<CachedFragment>(self.fragment_cache_key(item))?;
+                for key in entry.pages.iter() {
+                    if !cached_pages.contains_key(key) {
+                        cached_pages.insert(*key, cache.load::<String>(*key)?);
+                    }
+                }
+                Some(entry)
+            })
+            .collect::<Vec<_>>();
+
+        // Over time, fragments of a document may be scattered across many small pages. Pages are
+        // shipped as a whole, so at some point it pays off to compile everything again. This does
+        // not apply to preview positioning, where every page is a single fragment cropped to size.
+        if self.config.positioning != "preview" && cached_pages.len() > MAX_CACHED_PAGES {
+            log::info!(
+                "cached fragments are scattered across {} pages, recompiling everything",
+                cached_pages.len()
+            );
+            entries.iter_mut().for_each(|entry| *entry = None);
+            cached_pages.clear();
+        }
+
+        let missing = self
+            .fragments
+            .iter()
+            .zip(entries.iter())
+            .filter(|(item, entry)| entry.is_none() || matches!(item.ty, FragmentType::DontShow))
+            .map(|(item, _)| item)
+            .collect::<Vec<_>>();
+        let n_visible_fragments = self
+            .fragments
+            .iter()
+            .filter(|item| !matches!(item.ty, FragmentType::DontShow))
+            .count();
+        let n_cached_fragments = entries.iter().filter(|entry| entry.is_some()).count();
+        log::info!(
+            "reusing {}/{} fragments from cache",
+            n_cached_fragments,
+            n_visible_fragments
+        );
+
+        let (mut failed, mut warnings) = (BTreeMap::new(), vec![]);
+        if n_cached_fragments < n_visible_fragments {
+            let fresh = self.compile_tolerantly(&missing)?;
+            warnings = fresh.warnings;
+            let mut page_keys = vec![];
+            for page in fresh.pages.into_iter() {
+                let mut hasher = DefaultHasher::new();
+                "page".hash(&mut hasher);
+                page.hash(&mut hasher);
+                let key = hasher.finish();
+                cache.store(key, &page)?;
+                cached_pages.insert(key, page);
+                page_keys.push(key);
+            }
+            let mut fresh_regions = fresh.regions.into_iter().enumerate();
+            for (i, (item, entry)) in self.fragments.iter().zip(entries.iter_mut()).enumerate() {
+                if entry.is_some() && !matches!(item.ty, FragmentType::DontShow) {
+                    continue;
+                }
+                let (fresh_idx, regions) = fresh_regions.next().unwrap();
+                if let Some(error) = fresh.failed.get(&fresh_idx) {
+                    // Failed fragments are not cached, so they are retried in the next run.
+                    failed.insert(i, error.clone());
+                    continue;
+                }
+                if let FragmentType::DontShow = item.ty {
+                    continue;
+                }
+                // Only refer to the pages the fragment is on, so that the other pages of this run
+                // are neither loaded nor counted along with it later on.
+                let mut pages = vec![];
+                let regions = regions
+                    .into_iter()
+                    .map(|mut region| {
+                        let key = page_keys[region.page];
+                        region.page =
+                            pages
+                                .iter()
+                                .position(|&page| page == key)
+                                .unwrap_or_else(|| {
+                                    pages.push(key);
+                                    pages.len() - 1
+                                });
+                        region
+                    })
+                    .collect();
+                let fresh_entry = CachedFragment { pages, regions };
+                cache.store(self.fragment_cache_key(item), &fresh_entry)?;
+                *entry = Some(fresh_entry);
+            }
+        }
+
+        let mut rendered = Rendered {
+            pages: vec![],
+            regions: vec![],
+            failed,
+            warnings,
+        };
+        let mut page_indices: HashMap<u64, usize> = HashMap::new();
+        for entry in entries.into_iter() {
+            let mut regions = vec![];
+            if let Some(CachedFragment {
+                pages,
+                regions: entry_regions,
+            }) = entry
+            {
+                for mut region in entry_regions.into_iter() {
+                    let key = pages[region.page];
+                    region.page = *page_indices.entry(key).or_insert_with(|| {
+                        rendered.pages.push(cached_pages[&key].clone());
+                        rendered.pages.len() - 1
+                    });
+                    regions.push(region);
+                }
+            }
+            rendered.regions.push(regions);
+        }
+        Ok(rendered)
+    }
+
+    /// Renders all fragments, going through the render cache if configured.
+    fn render_all(&self) -> Result<Rendered> {
+        Ok(match &self.config.cache_dir {
+            Some(cache_dir) if self.config.cache_granularity == "fragment" => {
+                self.render_incrementally(&RenderCache::new(cache_dir)?)?
+            }
+            Some(cache_dir) => {
+                let cache = RenderCache::new(cache_dir)?;
+                let key = self.cache_key();
+                match cache.load::<Rendered>(key) {
+                    Some(rendered) if rendered.regions.len() == self.fragments.len() => {
+                        log::info!("reusing cached rendering {:016x}", key);
+                        rendered
+                    }
+                    _ => {
+                        let rendered =
+                            self.compile_tolerantly(&self.fragments.iter().collect::<Vec<_>>())?;
+                        cache.store(key, &rendered)?;
+                        rendered
+                    }
+                }
+            }
+            None => self.compile_tolerantly(&self.fragments.iter().collect::<Vec<_>>())?,
+        })
+    }
+
+    /// Returns the SVGs of the pages, optimized if configured.
+    fn optimized_pages<'r>(&self, rendered: &'r Rendered) -> Result<Vec<Cow<'r, [u8]>>> {
+        Ok(if self.config.optimizer.enabled {
+            rendered
+                .pages
+                .iter()
+                .map(|svg| -> Result<Cow<[u8]>> {
+                    let tree = svg_utils::parse_to_tree(svg.as_bytes())?;
+                    Ok(Cow::Owned(svg_optimize::optimize(
+                        &tree,
+                        self.config.optimizer.eps,
+                    )?))
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            rendered
+                .pages
+                .iter()
+                .map(|svg| Cow::Borrowed(svg.as_bytes()))
+                .collect()
+        })
+    }
+
+    /// Renders the fragments into standalone SVGs, in the same order as [`Self::fragments`].
+    fn render_standalone(&self) -> Result<Vec<RenderedFragment>> {
+        if self.fragments.is_empty() {
+            return Ok(vec![]);
+        }
+        let rendered = self.render_all()?;
+        // There is no report to return the warnings in, so they go to the log instead.
+        for warning in rendered.warnings.iter() {
+            log::warn!("latex warning {}", warning);
+        }
+        let croppers = self
+            .optimized_pages(&rendered)?
+            .iter()
+            .map(|svg| svg_utils::Cropper::new(svg))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut output = vec![];
+        for (i, regions) in rendered.regions.iter().enumerate() {
+            if let Some(error) = rendered.failed.get(&i) {
+                output.push(RenderedFragment {
+                    parts: vec![],
+                    error: Some(error.clone()),
+                });
+                continue;
+            }
+            let mut parts = vec![];
+            for region in regions {
+                let (x_range, y_range) = (region.x_range, region.y_range);
+                let (width, height) = (x_range.1 - x_range.0, y_range.1 - y_range.0);
+                let svg =
+                    croppers[region.page].crop((x_range.0, y_range.0, width, height), "", &[])?;
+                parts.push(SvgPart {
+                    svg: String::from_utf8(svg)?,
+                    width,
+                    height,
+                    depth: region.depth,
+                });
+            }
+            output.push(RenderedFragment { parts, error: None });
+        }
+        Ok(output)
+    }
+
+    /// Scans and modifies the tree in-place, replacing all inline and display maths with rendered
+    /// SVGs.
+    pub fn render_with_latex(mut self, tree: &'a mut Pandoc) -> Result<RenderReport> {
+        let final_node = self.walk_and_create_final_node(tree);
+
+        if self.fragments.is_empty() {
+            return Ok(RenderReport::default());
+        }
+
+        let rendered = self.render_all()?;
+
+        let report = RenderReport {
+            fragments: self.fragments.len(),
+            pages: rendered.pages.len(),
+            failed: rendered
+                .failed
+                .iter()
+                .map(|(idx, error)| (self.fragments[*idx].src.clone(), error.clone()))
+                .collect(),
+            warnings: rendered.warnings.clone(),
+        };
+
+        // A unique class name for each svg is important because HTMLs from multiple posts
+        // may be put together in the home page of a blog. Then the decompressing code of each page
+        // starts a race, each trying to modify every fragment image.
+        let svg_hashes = rendered
+            .pages
+            .iter()
+            .map(|svg| {
+                let mut hasher = DefaultHasher::new();
+                svg.as_bytes().hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect::<Vec<_>>();
+        let svg_class_names = svg_hashes
+            .iter()
+            .map(|hash| format!("jl-{}", base64::encode(hash.to_be_bytes())))
+            .collect::<Vec<_>>();
+
+        let svg_data = self.optimized_pages(&rendered)?;
+        // Under static output, fragments are cropped out of the pages into their own SVGs.
+        let croppers = if self.config.output == "static" {
+            svg_data
+                .iter()
+                .map(|svg| svg_utils::Cropper::new(svg))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            vec![]
+        };
+        let assets = match &self.config.asset_dir {
+            Some(dir) => Some(AssetDir::new(dir, &self.config.asset_url_prefix)?),
+            None => None,
+        };
+        // What the <img> tags of each page point at under script output. Without an asset
+        // directory, this is filled in by the decompressor script.
+        let page_urls = match &assets {
+            Some(assets) if self.config.output != "static" => svg_data
+                .iter()
+                .map(|svg| assets.write_svg(svg))
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => vec![String::new(); svg_data.len()],
+        };
+
+        for (i, (item, regions)) in self
+            .fragments
+            .iter_mut()
+            .zip(rendered.regions.iter())
+            .enumerate()
+        {
+            if let FragmentType::DontShow = item.ty {
+                // Skip dont shows.
+                item.replace_with_html("");
+                continue;
+            }
+            if let Some(error) = rendered.failed.get(&i) {
+                let html = match item.ty {
+                    FragmentType::InlineMath(_) | FragmentType::RawInline => format!(
+                        r#"<code class="jl-error" title="{error}" style="{style}">{src}</code>"#,
+                        error = html_escape::encode_double_quoted_attribute(error),
+                        style = self.config.error_style,
+                        src = html_escape::encode_text(&item.src),
+                    ),
+                    _ => format!(
+                        r#"<pre class="jl-error" title="{error}" style="{style}"><code>{src}</code></pre>"#,
+                        error = html_escape::encode_double_quoted_attribute(error),
+                        style = self.config.error_style,
+                        src = html_escape::encode_text(&item.src),
+                    ),
+                };
+                item.replace_with_html(&html);
+                continue;
+            }
+
+            let mut imgs = vec![];
+            // Prefixes of the IDs in inline SVGs, unique to each part of each fragment.
+            let mut id_prefixes = vec![];
+            for (j, region) in regions.iter().enumerate() {
+                let (x_range, y_range) = (region.x_range, region.y_range);
+                let extra_style = match item.ty {
+                    FragmentType::InlineMath(_) | FragmentType::RawInline => format!(
+                        "top:{depth:.2}pt;margin-top:{neg_depth:.2}pt;position:relative;{extra_style}",
+                        depth = region.depth - self.config.baseline_rise,
+                        neg_depth = self.config.baseline_rise - region.depth,
+                        extra_style = self.config.extra_style_inline
+                    ),
+                    FragmentType::DisplayMath | FragmentType::RawBlock => {
+                        self.config.extra_style_display.clone()
+                    }
+                    FragmentType::DontShow => unreachable!(),
+                };
+                let ty = if item.ty.is_inline() {
+                    "inline"
+                } else {
+                    "display"
+                };
+                let (width, height) = (x_range.1 - x_range.0, y_range.1 - y_range.0);
+                if self.config.output != "static" {
+                    imgs.push(formatdoc!(
+                        r##"<img src="{url}#svgView(viewBox({x:.2},{y:.2},{width:.2},{height:.2}))"
+                             class="{class_name} jl-{ty}" alt = "{alt}"
+                             style="width:{width:.2}pt;height:{height:.2}pt;
+                             display:inline;{extra_style}">"##,
+                        url = page_urls[region.page],
+                        x = x_range.0,
+                        y = y_range.0,
+                        class_name = svg_class_names[region.page],
+                        alt = html_escape::encode_text(&item.src),
+                        extra_style = extra_style
+                    ));
+                    continue;
+                }
+                let view_box = (x_range.0, y_range.0, width, height);
+                let style = format!(
+                    "width:{:.2}pt;height:{:.2}pt;display:inline;{}",
+                    width, height, extra_style
+                );
+                if self.config.static_embed == "svg" {
+                    let class = format!("jl-{}", ty);
+                    let id_prefix = format!("jl{:x}f{}p{}-", svg_hashes[region.page], i, j);
+                    let svg = croppers[region.page].crop(
+                        view_box,
+                        &id_prefix,
+                        &[
+                            ("class", &class),
+                            ("style", &style),
+                            ("role", "img"),
+                            ("aria-label", &item.src),
+                        ],
+                    )?;
+                    let svg = String::from_utf8(svg)?;
+                    id_prefixes.push(id_prefix);
+                    // Drop the XML declaration.
+                    imgs.push(svg[svg.find("<svg").unwrap_or(0)..].trim_end().to_string());
+                } else {
+                    let svg = croppers[region.page].crop(view_box, "", &[])?;
+                    let src = match &assets {
+                        Some(assets) => assets.write_svg(&svg)?,
+                        None => format!("data:image/svg+xml;base64,{}", base64::encode(svg)),
+                    };
+                    imgs.push(format!(
+                        r#"<img src="{src}" class="jl-{ty}" alt="{alt}" style="{style}">"#,
+                        src = html_escape::encode_double_quoted_attribute(&src),
+                        alt = html_escape::encode_double_quoted_attribute(&item.src),
+                    ));
+                }
+            }
+            let html = match item.ty {
+                FragmentType::InlineMath(_) | FragmentType::RawInline => imgs.join(""),
+                FragmentType::DisplayMath | FragmentType::RawBlock => {
+                    format!(
+                        r#"<div class="jl-display-div" style="text-align:center;">{}</div>"#,
+                        imgs.join("<br>")
+                    )
+                }
+                FragmentType::DontShow => unreachable!(),
+            };
+            // Duplicate inline fragments share the rendering, but each copy of an inline SVG needs
+            // its own IDs.
+            item.replace_with_html_each(|k| {
+                let mut html = html.clone();
+                if k > 0 {
+                    for prefix in id_prefixes.iter() {
+                        let unique = format!("{}r{}-", prefix.trim_end_matches('-'), k);
+                        html = html.replace(prefix.as_str(), &unique);
+                    }
+                }
+                html
+            });
+        }
+
+        if self.config.output == "static" || assets.is_some() {
+            // No decompressor needed.
+            return Ok(report);
+        }
+
+        let codec = self.config.compression.as_str();
+        let mut decompress_script = String::new();
+        for (i, (svg, class_name)) in svg_data.into_iter().zip(svg_class_names).enumerate() {
+            let start = Instant::now();
+            let original_size = svg.len();
+            let svg_encoded = base64::encode(compress(codec, &svg)?);
+            decompress_script.push_str(&match codec {
+                "lzma" => formatdoc!(
+                    r##"
+                        var w{page}=new Worker(s);
+                        w{page}.onmessage=f("{class_name}");
+                        w{page}.postMessage("{svg}");
+                    "##,
+                    page = i + 1,
+                    svg = svg_encoded,
+                    class_name = class_name
+                ),
+                "none" => formatdoc!(
+                    r##"
+                        f("{class_name}")({{data:u("{svg}")}});
+                    "##,
+                    svg = svg_encoded,
+                    class_name = class_name
+                ),
+                _ => formatdoc!(
+                    r##"
+                        d("{svg}").then(function(b){{f("{class_name}")({{data:b}})}});
+                    "##,
+                    svg = svg_encoded,
+                    class_name = class_name
+                ),
+            });
+
+            log::info!(
+                "SVG for page {} compressed ({}) from {} down to {} (base64 encoded) in {}s",
+                i + 1,
+                codec,
+                ByteSize::b(original_size as u64),
+                ByteSize::b(svg_encoded.len() as u64),
+                start.elapsed().as_secs_f64()
+            );
+            if self.config.compare_compression {
+                for other in ["lzma", "gzip", "deflate", "none"] {
+                    let start = Instant::now();
+                    let size = base64::encode(compress(other, &svg)?).len();
+                    log::info!(
+                        "    {:<7} {:>10} (base64 encoded) in {:.3}s",
+                        other,
+                        ByteSize::b(size as u64).to_string(),
+                        start.elapsed().as_secs_f64()
+                    );
+                }
+            }
+        }
+
+        let decoder = match codec {
+            "lzma" => {
+                let lzma_js = if self.config.lzma_js == "bundled" {
+                    minify_js(LZMA_DECODER_JS)
+                } else {
+                    format!(
+                        "importScripts({})",
+                        serde_json::to_string(&self.config.lzma_js_path)?
+                    )
+                };
+                let worker = format!(
+                    "{};onmessage=function(a){{LZMA.decompress(Uint8Array.from(atob(a.data),function(a){{return a.charCodeAt(0)}}),function(a,b){{postMessage(a)}})}}",
+                    lzma_js
+                );
+                // A JSON string is a valid JS string literal. Escape "</" so that it cannot close
+                // the <script> tag.
+                format!(
+                    r#"var s=URL.createObjectURL(new Blob([{}], {{type: "text/javascript"}}));"#,
+                    serde_json::to_string(&worker)?.replace("</", "<\\/")
+                )
+            }
+            "none" => r#"var u=function(a){return Uint8Array.from(atob(a),function(a){return a.charCodeAt(0)})};"#.into(),
+            // Decompressed natively by the browser.
+            _ => format!(
+                r#"var d=function(a){{return new Response(new Blob([Uint8Array.from(atob(a),function(a){{return a.charCodeAt(0)}})]).stream().pipeThrough(new DecompressionStream("{}"))).arrayBuffer()}};"#,
+                codec
+            ),
+        };
+        let final_code = formatdoc!(
+            r##"
+            <script {extra_attribs}>
+                (function(){{
+                    {decoder}
+                    var f=function(a){{return function(e){{for(var f=URL.createObjectURL(new Blob([typeof e.data==="string"?e.data:new Uint8Array(e.data)],{{type:"image/svg+xml"}})),c=document.getElementsByClassName(a),b=0;b<c.length;b++){{var d=c[b].src.indexOf("#");-1!=d&&(c[b].src=f+c[b].src.substring(d))}}}}}};
+                    {decompress_script}
+                }}());
+            </script>
+            "##,
+            extra_attribs = self.config.script_extra_attributes,
+            decoder = decoder,
+            decompress_script = decompress_script
+        );
+        *final_node = Block::RawBlock("html".into(), final_code);
+        Ok(report)
+    }
+
+    /// Like [`Self::compile`], but under `on_error = "placeholder"` fragments that LaTeX fails to
+    /// compile are left out and reported in [`Rendered::failed`] instead of failing the whole
+    /// document.
+    ///
+    /// Failing fragments are identified through the LaTeX log when possible. Otherwise they are
+    /// found by bisecting: the shortest prefix of the fragments that fails to compile ends with
+    /// the culprit.
+    fn compile_tolerantly(&self, fragments: &[&Fragment]) -> Result<Rendered> {
+        if self.config.on_error != "placeholder" {
+            return self.compile(fragments);
+        }
+        let mut failed = BTreeMap::new();
+        loop {
+            let included = (0..fragments.len())
+                .filter(|i| !failed.contains_key(i))
+                .collect::<Vec<_>>();
+            let subset = included.iter().map(|&i| fragments[i]).collect::<Vec<_>>();
+            if subset
+                .iter()
+                .all(|item| matches!(item.ty, FragmentType::DontShow))
+            {
+                // Nothing left to show.
+                return Ok(Rendered {
+                    pages: vec![],
+                    regions: vec![vec![]; fragments.len()],
+                    failed,
+                    warnings: vec![],
+                });
+            }
+            let error = match self.compile(&subset) {
+                Ok(rendered) => {
+                    let mut regions = vec![vec![]; fragments.len()];
+                    for (i, item_regions) in included.into_iter().zip(rendered.regions) {
+                        regions[i] = item_regions;
+                    }
+                    return Ok(Rendered {
+                        pages: rendered.pages,
+                        regions,
+                        failed,
+                        warnings: rendered.warnings,
+                    });
+                }
+                Err(error) => error,
+            };
+            // Only LaTeX errors are handled here. Other errors (e.g. a missing dvisvgm) are not
+            // caused by any particular fragment.
+            let culprits = match error.downcast_ref::<LaTeXError>() {
+                None => return Err(error),
+                Some(latex_error) if latex_error.in_preamble() => return Err(error),
+                Some(latex_error) if !latex_error.failed_fragments().is_empty() => {
+                    latex_error.failed_fragments()
+                }
+                Some(_) => match self.bisect(&subset) {
+                    Some(idx) => BTreeMap::from([(idx, error.to_string())]),
+                    None => return Err(error),
+                },
+            };
+            for (idx, details) in culprits {
+                failed.insert(included[idx], details);
+            }
+        }
+    }
+
+    /// Finds the fragment that ends the shortest failing prefix of the given fragments, assuming
+    /// that compiling all of them fails with a [`LaTeXError`].
+    fn bisect(&self, fragments: &[&Fragment]) -> Option<usize> {
+        // A prefix without visible fragments does not produce any output and hence cannot be
+        // compiled on its own. Assume it is fine. Likewise, only LaTeX errors make a prefix fail:
+        // other errors, e.g. from dvisvgm, come after LaTeX compiled the prefix successfully.
+        let compiles = |len: usize| {
+            fragments[..len]
+                .iter()
+                .all(|item| matches!(item.ty, FragmentType::DontShow))
+                || match self.compile(&fragments[..len]) {
+                    Ok(_) => true,
+                    Err(error) => !error.is::<LaTeXError>(),
+                }
+        };
+        // Invariant: the prefix of length `low` compiles and the prefix of length `high` does not.
+        let (mut low, mut high) = (0, fragments.len());
+        while high - low > 1 {
+            let mid = (low + high) / 2;
+            if compiles(mid) {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        high.checked_sub(1)
+    }
+
+    /// Compiles the given fragments with LaTeX, converts the output to SVGs and locates every
+    /// fragment on the resulting pages.
+    fn compile(&self, fragments: &[&Fragment]) -> Result<Rendered> {
+        // In TeX 1 in = 72.72 pt = 72 bp, while in SVG 1 in = 72 pt.
+        // Due to different definitions of pt we need a small scaling factor here.
+        // See https://github.com/mgieseki/dvisvgm/issues/185
+        const TEX2SVG_SCALING: f64 = 72.0 / 72.27;
+
+        let (source_str, lines) = self.generate_latex_with_line_mappings(fragments);
+        let working_dir = match self.config.output_folder {
+            Some(_) => None,
+            None => Some(TempDir::new()?),
+        };
+        let working_path = match &working_dir {
+            Some(working_dir) => working_dir.path().to_path_buf(),
+            None => Path::new(self.config.output_folder.as_ref().unwrap()).to_path_buf(),
+        }
+        .canonicalize()?;
+        let source_path = working_path.join("source.tex");
+
+        // eprintln!("{}", source_str);
+        {
+            let mut source = File::create(&source_path)?;
+            source.write_all(source_str.as_bytes())?;
+        }
+        let mut latex_command = Command::new(&self.config.latex);
+        if self.config.mode == "dvi" {
+            latex_command.arg("-output-format=dvi");
+        } else if self.config.mode == "xdv" {
+            latex_command.arg("--no-pdf");
+        }
+        let pdf_path = working_path.join(if self.config.mode == "pdf" {
+            "source.pdf"
+        } else if self.config.mode == "dvi" {
+            "source.dvi"
+        } else {
+            "source.xdv"
+        });
+        if self.config.positioning == "synctex" {
+            latex_command.arg("-synctex=-1");
+        }
+        let latex_command = latex_command
+            .args(["-interaction=nonstopmode", source_path.to_str().unwrap()])
+            .current_dir(&working_path)
+            .output()?;
+        if !latex_command.status.success() {
+            let stdout = String::from_utf8_lossy(&latex_command.stdout);
+            let log = fs::read_to_string(working_path.join("source.log"))
+                .unwrap_or_else(|_| stdout.to_string());
+            let errors = tex_log::parse_errors(&log);
+            if errors.is_empty() {
+                bail!("fail to run latex: {stdout}");
+            }
+            return Err(LaTeXError::new(fragments, &lines, errors).into());
+        }
+        let mut warnings = vec![];
+        if let Ok(log) = fs::read_to_string(working_path.join("source.log")) {
+            let log_warnings = tex_log::parse_warnings(&log);
+            if self.config.strict && !log_warnings.is_empty() {
+                let errors = log_warnings
+                    .into_iter()
+                    .map(|warning| TeXError {
+                        message: warning.message,
+                        line: warning.line,
+                        context: vec![],
+                    })
+                    .collect();
+                return Err(LaTeXError::new(fragments, &lines, errors).into());
+            }
+            let descriptions = fragments
+                .iter()
+                .map(|item| item.describe())
+                .collect::<Vec<_>>();
+            for warning in log_warnings {
+                let location = locate_line(&lines, warning.line).describe(&descriptions);
+                warnings.push(format!("in {}: {}", location, warning.message));
+            }
+        }
+
+        let mut dvisvgm_command = Command::new(&self.config.dvisvgm);
+        if self.config.mode == "pdf" {
+            dvisvgm_command.arg("--pdf");
+        } else {
+            dvisvgm_command.arg("--font-format=ttf");
+        }
+        let dvisvgm_command = dvisvgm_command
+            .args([
+                "--stdout",
+                "--relative", // Empirically reduces SVG size.
+                "--page=1-",  // Convert all pages.
+                pdf_path.to_str().unwrap(),
+            ])
+            .current_dir(&working_path)
+            .output()?;
+        if !dvisvgm_command.status.success() {
+            bail!(
+                "fail to run dvisvgm: {}",
+                String::from_utf8_lossy(&dvisvgm_command.stderr).trim()
+            );
+        }
+        // Split svgs because we might have multiple pages.
+        let svg_data = svg_utils::split_svgs(&dvisvgm_command.stdout)?;
+        let svgs = svg_data
+            .iter()
+            .map(|svg_data| svg_utils::parse_to_tree(svg_data))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let bboxes = svgs
+            .iter()
+            .map(svg_utils::paths_to_bboxes)
+            .collect::<Vec<_>>();
+        let (scanner, markers, previews) = match self.config.positioning.as_str() {
+            "markers" => (
+                None,
+                markers::read(&working_path.join(markers::FILE_NAME))?,
+                None,
+            ),
+            "preview" => {
+                let log = fs::read_to_string(working_path.join("source.log"))
+                    .context("reading the LaTeX log")?;
+                (None, HashMap::new(), Some(preview::read(&log)?))
+            }
+            _ => (
+                Some(Scanner::new(pdf_path, &working_path)?),
+                HashMap::new(),
+                None,
+            ),
+        };
+        // Under the preview package, the shown fragments are shipped out in order, one per page.
+        let mut preview_page = 0;
+        let mut seen_boxes = HashSet::new();
+        let mut fragment_regions = vec![];
+
+        for (i, (item, line_range)) in fragments.iter().zip(lines).enumerate() {
+            if let FragmentType::DontShow = item.ty {
+                fragment_regions.push(vec![]);
+                continue;
+            }
+
+            #[derive(Clone, Debug)]
+            struct TeXRegion {
+                x_range: (f64, f64),
+                y_range: (f64, f64),
+                baseline: f64,
+                baseline_width: f64,
+            }
+
+            let mut regions: BTreeMap<u32, TeXRegion> = BTreeMap::new();
+
+            if let Some(scanner) = &scanner {
+                // TeX tags the lines of a paragraph with the line where the paragraph ends, i.e. the
+                // blank line after the fragment.
+                for tb in scanner.query(line_range.start..line_range.end + 1)? {
+                    let area = tb.width * (tb.height + tb.depth);
+                    if area.into_inner() <= 1e-6 {
+                        // Skip zero-area boxes. They may be generated by the TeX page breaker and
+                        // do not actually correspond to anything in our source file. Also they
+                        // wouldn't contribute to updating the region of the page anyways.
+                        continue;
+                    }
+                    if seen_boxes.contains(&tb) {
+                        // Continue if we have seen this box -- then probably that's SyncTeX's
+                        // fault
+                        continue;
+                    }
+                    seen_boxes.insert(tb.clone());
+
+                    let (x_low, x_high) = (tb.h.into_inner(), (tb.h + tb.width).into_inner());
+                    let (y_low, y_high) = (
+                        (tb.v - tb.height).into_inner(),
+                        (tb.v + tb.depth).into_inner(),
+                    );
+                    regions
+                        .entry(tb.page)
+                        .and_modify(|r| {
+                            r.x_range = (r.x_range.0.min(x_low), r.x_range.1.max(x_high));
+                            r.y_range = (r.y_range.0.min(y_low), r.y_range.1.max(y_high));
+                            if tb.width.into_inner() > r.baseline_width {
+                                r.baseline_width = tb.width.into_inner();
+                                r.baseline = tb.v.into_inner();
+                            }
+                        })
+                        .or_insert_with(|| TeXRegion {
+                            x_range: (x_low, x_high),
+                            y_range: (y_low, y_high),
+                            baseline: tb.v.into(),
+                            baseline_width: tb.width.into(),
+                        });
+                }
+            } else if let Some(previews) = &previews {
+                preview_page += 1;
+                if preview_page as usize > svgs.len() {
+                    bail!("the preview package shipped out fewer pages than there are fragments");
+                }
+                if let Some(snippet) = previews.snippets.get(&i) {
+                    let [left, bottom, right, top] = previews.margins;
+                    let baseline = top + snippet.height;
+                    let width = left + snippet.width + right;
+                    regions.insert(
+                        preview_page,
+                        TeXRegion {
+                            x_range: (0.0, width),
+                            y_range: (0.0, baseline + snippet.depth + bottom),
+                            baseline,
+                            baseline_width: width,
+                        },
+                    );
+                }
+            } else if let Some(marker) = markers.get(&i) {
+                regions.insert(
+                    marker.page,
+                    TeXRegion {
+                        x_range: (marker.h, marker.h + marker.width),
+                        y_range: (marker.v - marker.height, marker.v + marker.depth),
+                        baseline: marker.v,
+                        baseline_width: marker.width,
+                    },
+                );
+            }
+
+            if regions.is_empty() {
+                bail!("no boxes for {}", item.src);
+            }
+            if item.ty.is_inline() && regions.len() > 1 {
+                bail!(
+                    "inline fragments '{}' spans multiple pages {:?} (did you disable page numbering?)",
+                    item.src,
+                    regions.keys().collect::<Vec<_>>()
+                );
+            }
+
+            let mut item_regions = vec![];
+            for (
+                page,
+                TeXRegion {
+                    mut x_range,
+                    mut y_range,
+                    mut baseline,
+                    ..
+                },
+            ) in regions.into_iter()
+            {
+                if !(1..=svgs.len()).contains(&(page as usize)) {
+                    bail!(
+                        "{} is reported on page {}, but there are only {} pages",
+                        item.src,
+                        page,
+                        svgs.len()
+                    );
+                }
+                let svg_idx = page as usize - 1;
+                // For whatever reason, the coordinate system of SVGs resulting from PDF
+                // conversion is translated.
+                let (x_base, y_base) = if self.config.mode == "pdf" {
+                    let view_box = &svgs[svg_idx].svg_node().view_box.rect;
+                    (view_box.left(), view_box.top())
+                } else {
+                    (0.0, 0.0)
+                };
+                // Convert everything from TeX coordinates to SVG coordinates.
+                y_range = (
+                    y_range.0 * TEX2SVG_SCALING + y_base,
+                    y_range.1 * TEX2SVG_SCALING + y_base,
+                );
+                x_range = svg_utils::x_range_for_y_range(
+                    &bboxes[svg_idx],
+                    y_range.0,
+                    y_range.1,
+                    self.config.y_range_tol,
+                    self.config.x_range_margin,
+                )
+                .unwrap_or((
+                    x_range.0 * TEX2SVG_SCALING + x_base,
+                    x_range.1 * TEX2SVG_SCALING + x_base,
+                ));
+                baseline = baseline * TEX2SVG_SCALING + y_base;
+
+                if let FragmentType::DisplayMath | FragmentType::RawBlock = item.ty {
+                    y_range = svg_utils::refine_y_range(
+                        &bboxes[svg_idx],
+                        y_range.0,
+                        y_range.1,
+                        self.config.y_range_tol,
+                    );
+                }
+                y_range.0 -= self.config.y_range_margin;
+                y_range.1 += self.config.y_range_margin;
+
+                let depth = match item.ty {
+                    FragmentType::InlineMath(_) | FragmentType::RawInline => y_range.1 - baseline,
+                    FragmentType::DisplayMath | FragmentType::RawBlock => 0.0,
+                    FragmentType::DontShow => unreachable!(),
+                };
+                item_regions.push(Region {
+                    page: svg_idx,
+                    x_range,
+                    y_range,
+                    depth,
+                });
+            }
+            fragment_regions.push(item_regions);
+        }
+
+        Ok(Rendered {
+            pages: svg_data
+                .iter()
+                .map(|data| String::from_utf8(data.to_vec()))
+                .collect::<Result<Vec<_>, _>>()
+                .context("dvisvgm produced non UTF-8 output")?,
+            regions: fragment_regions,
+            failed: BTreeMap::new(),
+            warnings,
+        })
+    }
+
+    /// Prepares the tree for LaTeX output. Maths are left for Pandoc to handle, while %raw and
+    /// %dontshow fragments become raw TeX. The packages loaded in the preamble are added to
+    /// `header-includes` so that the fragments compile the same way as they do in our own .tex.
+    pub fn pass_through_to_latex(mut self, tree: &'a mut Pandoc) -> Result<()> {
+        let packages = usepackage_lines(&self.config.preamble);
+        if !packages.is_empty() {
+            let include = MetaValue::MetaBlocks(vec![Block::RawBlock("latex".into(), packages)]);
+            match tree.meta.get_mut("header-includes") {
+                Some(MetaValue::MetaList(header_includes)) => header_includes.push(include),
+                Some(header_includes) => {
+                    let existing = std::mem::replace(header_includes, MetaValue::MetaList(vec![]));
+                    *header_includes = MetaValue::MetaList(vec![existing, include]);
+                }
+                None => {
+                    tree.meta
+                        .insert("header-includes".into(), MetaValue::MetaList(vec![include]));
+                }
+            }
+        }
+
+        ast::walk_blocks(&mut self, &mut tree.blocks);
+        for item in self.fragments.iter_mut() {
+            let src = match item.ty {
+                FragmentType::InlineMath(_)
+                | FragmentType::DisplayMath
+                | FragmentType::RawInline => continue,
+                FragmentType::RawBlock => item.src.clone(),
+                FragmentType::DontShow => item
+                    .src
+                    .trim_start()
+                    .trim_start_matches("%dontshow")
+                    .trim_start()
+                    .to_string(),
+            };
+            for node in item.refs.iter_mut() {
+                match node {
+                    FragmentNodeRef::Inline(node) => {
+                        **node = Inline::RawInline("latex".into(), src.clone())
+                    }
+                    FragmentNodeRef::Block(node) => {
+                        **node = Block::RawBlock("latex".into(), src.clone())
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks the tree and look for math nodes. Also appends an empty final node to the document and
+    /// returns the reference to it, which we will replace with the decompressor script later. Due to
+    /// the borrow checker this is the only place we can add stuff to the tree: once the fragments
+    /// hold references into `tree.blocks`, it can no longer be modified.
+    fn walk_and_create_final_node(&mut self, tree: &'a mut Pandoc) -> &'a mut Block {
+        if self.config.meta_keys.iter().any(|key| key == "title")
+            && !tree.meta.contains_key("pagetitle")
+        {
+            // The <title> of the page cannot contain images. Pandoc uses the plain text of the
+            // title there, which would otherwise lose the maths.
+            if let Some(MetaValue::MetaInlines(title)) = tree.meta.get("title") {
+                let pagetitle = json!({"t": "MetaString", "c": ast::stringify(title)});
+                tree.meta
+                    .insert("pagetitle".into(), MetaValue::Other(pagetitle));
+            }
+        }
+        tree.blocks
+            .push(Block::RawBlock("html".into(), String::new()));
+        let (final_node, blocks) = tree.blocks.split_last_mut().unwrap();
+        ast::walk_blocks(self, blocks);
+
+        // The metadata comes after the body, so that its fragments can use the macros defined by
+        // the %dontshow blocks of the body.
+        for (key, value) in tree.meta.iter_mut() {
+            if self.config.meta_keys.contains(key) {
+                ast::walk_meta(self, value);
+            }
+        }
+        final_node
+    }
+
+    /// Walks a node under the given style, restoring the current style afterwards.
+    fn with_style(&mut self, style: Style, walk: impl FnOnce(&mut Self)) {
+        let outer = std::mem::replace(&mut self.style, style);
+        walk(self);
+        self.style = outer;
+    }
+}
+
+impl<'a> MutVisitor<'a> for FragmentRenderer<'a> {
+    fn visit_block(&mut self, block: &'a mut Block) {
+        match block {
+            // Pandoc uses "tex" for raw TeX in Markdown, but "latex" in e.g. the LaTeX reader.
+            Block::RawBlock(format, text) if format == "tex" || format == "latex" => {
+                let ty = if text.trim_start().starts_with("%dontshow") {
+                    FragmentType::DontShow
+                } else {
+                    FragmentType::RawBlock
+                };
+                let text = text.clone();
+                self.add_fragment(ty, &text, FragmentNodeRef::Block(block));
+            }
+            Block::Header(level, ..) => {
+                let style = self.style.clone().push(StyleElement::Header(*level));
+                self.with_style(style, |this| ast::walk_block(this, block));
+            }
+            Block::BlockQuote(_) => {
+                let style = self.style.clone().push(StyleElement::Quote);
+                self.with_style(style, |this| ast::walk_block(this, block));
+            }
+            _ => ast::walk_block(self, block),
+        }
+    }
+
+    fn visit_inline(&mut self, inline: &'a mut Inline) {
+        match inline {
+            Inline::Math(ty, text) => {
+                let ty = match ty {
+                    // A better idea would be to use persistent list which avoids cloning and much
+                    // of the push-and-pop boilerplates. But empirically style don't have a lot of
+                    // elements.
+                    MathType::InlineMath => FragmentType::InlineMath(self.style.clone()),
+                    MathType::DisplayMath => {
+                        let trimmed_text = text.trim_start();
+                        if trimmed_text.starts_with("%raw") {
+                            FragmentType::RawBlock
+                        } else if trimmed_text.starts_with("%dontshow") {
+                            FragmentType::DontShow
+                        } else {
+                            FragmentType::DisplayMath
+                        }
+                    }
+                };
+                let text = text.clone();
+                self.add_fragment(ty, &text, FragmentNodeRef::Inline(inline));
+            }
+            Inline::RawInline(format, text) if format == "tex" || format == "latex" => {
+                // Pandoc used to drop raw TeX for HTML, which is right for commands that produce
+                // no output. Rendering them would only take the boxes of the fragments around.
+                let ty = if is_invisible(text, &self.config.invisible_commands) {
+                    FragmentType::DontShow
+                } else {
+                    FragmentType::RawInline
+                };
+                let text = text.clone();
+                self.add_fragment(ty, &text, FragmentNodeRef::Inline(inline));
+            }
+            Inline::Emph(_) => {
+                let style = self.style.clone().push(StyleElement::Emph);
+                self.with_style(style, |this| ast::walk_inline(this, inline));
+            }
+            Inline::Strong(_) => {
+                let style = self.style.clone().push(StyleElement::Strong);
+                self.with_style(style, |this| ast::walk_inline(this, inline));
+            }
+            // The content of a footnote is not part of the surrounding text, so it does not
+            // inherit its style.
+            Inline::Note(_) => self.with_style(Style::Plain, |this| ast::walk_inline(this, inline)),
+            // TODO: render underlines differently in latex.
+            _ => ast::walk_inline(self, inline),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usepackage_lines_without_geometry() {
+        let preamble = indoc::indoc! {r"
+            \documentclass[12pt]{article}
+            \usepackage[top=0cm, paperheight=16000pt]{geometry}
+            \usepackage{amsmath,geometry}
+              \usepackage[utf8]{inputenc} % encoding
+            \usepackage{ geometry , bm }
+            \newcommand{\R}{\mathbb{R}}
+            \begin{document}"
+        };
+        assert_eq!(
+            usepackage_lines(preamble),
+            indoc::indoc! {r"
+                \usepackage{amsmath}
+                \usepackage[utf8]{inputenc} % encoding
+                \usepackage{bm}"
+            }
+        );
+    }
+
+    #[test]
+    fn locate_lines() {
+        let lines = [5..6, 7..10];
+        assert_eq!(locate_line(&lines, Some(2)), Location::Preamble(2));
+        assert_eq!(locate_line(&lines, Some(5)), Location::Fragment(0));
+        // The blank line after a fragment belongs to the fragment.
+        assert_eq!(locate_line(&lines, Some(6)), Location::Fragment(0));
+        assert_eq!(locate_line(&lines, Some(10)), Location::Fragment(1));
+        assert_eq!(locate_line(&lines, Some(12)), Location::Postamble(12));
+        assert_eq!(locate_line(&lines, None), Location::Unknown);
+    }
+
+    #[test]
+    fn latex_error_attribution() {
+        let fragments = [
+            Fragment {
+                ty: FragmentType::InlineMath(Style::Plain),
+                src: r"\foo".into(),
+                refs: vec![],
+            },
+            Fragment {
+                ty: FragmentType::DisplayMath,
+                src: "x^".into(),
+                refs: vec![],
+            },
+        ];
+        let fragments = fragments.iter().collect::<Vec<_>>();
+        let lines = [5..6, 7..10];
+        let error = |line| TeXError {
+            message: "Undefined control sequence.".into(),
+            line,
+            context: vec![],
+        };
+
+        let latex_error = LaTeXError::new(&fragments, &lines, vec![error(Some(8)), error(None)]);
+        assert!(!latex_error.in_preamble());
+        assert_eq!(
+            latex_error
+                .failed_fragments()
+                .into_keys()
+                .collect::<Vec<_>>(),
+            [1]
+        );
+        let message = latex_error.to_string();
+        assert!(message.contains("in display math fragment `x^`:"));
+        assert!(message.contains("in unknown location:"));
+
+        let latex_error = LaTeXError::new(&fragments, &lines, vec![error(Some(3))]);
+        assert!(latex_error.in_preamble());
+        assert!(latex_error.failed_fragments().is_empty());
+        assert!(latex_error
+            .to_string()
+            .contains("in the preamble (line 3):"));
+    }
+
+    #[test]
+    fn maths_in_every_container_are_collected() {
+        let math = |src: &str| json!({"t": "Math", "c": [{"t": "InlineMath"}, src]});
+        let wrap = |t: &str, src: &str| json!({"t": t, "c": [math(src)]});
+        let attr = json!(["", [], []]);
+        let target = json!(["https://example.com", ""]);
+        let para = json!({"t": "Para", "c": [
+            {"t": "Note", "c": [{"t": "Para", "c": [math("note")]}]},
+            {"t": "Span", "c": [attr, [math("span")]]},
+            {"t": "Quoted", "c": [{"t": "DoubleQuote"}, [math("quoted")]]},
+            {"t": "Cite", "c": [
+                [{
+                    "citationId": "knuth",
+                    "citationPrefix": [math("prefix")],
+                    "citationSuffix": [math("suffix")],
+                    "citationMode": {"t": "NormalCitation"},
+                    "citationNoteNum": 1,
+                    "citationHash": 0
+                }],
+                [math("cite"), {"t": "Str", "c": "@knuth"}]
+            ]},
+            {"t": "Link", "c": [attr, [math("link")], target]},
+            {"t": "Image", "c": [attr, [math("image")], target]},
+            wrap("Underline", "underline"),
+            wrap("SmallCaps", "smallcaps"),
+            wrap("Strikeout", "strikeout"),
+            wrap("Subscript", "subscript"),
+            wrap("Superscript", "superscript"),
+        ]});
+        let mut tree: Pandoc = serde_json::from_value(json!({
+            "pandoc-api-version": [1, 23, 1],
+            "meta": {},
+            "blocks": [para]
+        }))
+        .unwrap();
+
+        let mut renderer = FragmentRenderer::new(ConfigBuilder::new().unwrap().build().unwrap());
+        renderer.walk_and_create_final_node(&mut tree);
+        let srcs = renderer
+            .fragments
+            .iter()
+            .map(|fragment| fragment.src.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            srcs,
+            [
+                "note",
+                "span",
+                "quoted",
+                "prefix",
+                "suffix",
+                "cite",
+                "link",
+                "image",
+                "underline",
+                "smallcaps",
+                "strikeout",
+                "subscript",
+                "superscript"
+            ]
+        );
+    }
+
+    #[test]
+    fn compression_round_trip() {
+        use flate2::read::{GzDecoder, ZlibDecoder};
+        use xz2::{read::XzDecoder, stream::Stream};
+
+        let data = "<svg><path d='M73 73H92V80H73Z'/></svg>\n".repeat(100);
+        let data = data.as_bytes();
+        for codec in ["lzma", "gzip", "deflate", "none"] {
+            let compressed = compress(codec, data).unwrap();
+            let mut decompressed = vec![];
+            match codec {
+                "lzma" => {
+                    let stream = Stream::new_lzma_decoder(u64::MAX).unwrap();
+                    XzDecoder::new_stream(&compressed[..], stream).read_to_end(&mut decompressed)
+                }
+                "gzip" => GzDecoder::new(&compressed[..]).read_to_end(&mut decompressed),
+                "deflate" => ZlibDecoder::new(&compressed[..]).read_to_end(&mut decompressed),
+                _ => (&compressed[..]).read_to_end(&mut decompressed),
+            }
+            .unwrap();
+            assert_eq!(decompressed, data, "{}", codec);
+        }
+
+        // The decoder of the lzma package reads the legacy .lzma format, which starts with the
+        // properties of the default preset rather than with the magic bytes of .xz.
+        assert_eq!(compress("lzma", data).unwrap()[0], 0x5d);
+        assert_eq!(compress("gzip", data).unwrap()[..2], [0x1f, 0x8b]);
+        // DecompressionStream("deflate") expects the zlib format of RFC 1950, i.e. deflate with a
+        // 32K window and a header checksum, rather than raw deflate.
+        let zlib = compress("deflate", data).unwrap();
+        assert_eq!(zlib[0], 0x78);
+        assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0);
+        assert!(compress("brotli", data).is_err());
+    }
+
+    #[test]
+    fn api_versions() {
+        let read = |version: Value| {
+            ApiVersion::read(&json!({"pandoc-api-version": version, "meta": {}, "blocks": []}))
+        };
+        let error = ApiVersion::read(&json!([{"unMeta": {}}, []])).unwrap_err();
+        assert!(error.to_string().contains("missing pandoc-api-version"));
+        let error = read(json!([2, 0])).unwrap_err();
+        assert!(error.to_string().contains("only 1.x is supported"));
+        let error = read(json!([1, 16])).unwrap_err();
+        assert!(error.to_string().contains("please upgrade Pandoc"));
+        assert!(read(json!([1])).is_err());
+        assert_eq!(read(json!([1, 17, 5, 4])).unwrap(), ApiVersion(1, 17));
+        assert_eq!(read(json!([1, 23, 1])).unwrap(), ApiVersion(1, 23));
+    }
+
+    #[test]
+    fn invisible_raw_inlines() {
+        let config = ConfigBuilder::new().unwrap().build().unwrap();
+        let commands = &config.invisible_commands;
+        assert!(is_invisible(r"\label{eq:foo}", commands));
+        assert!(is_invisible(
+            r" \index{Lie algebra!{$\mathfrak{g}$}} \hfill",
+            commands
+        ));
+        assert!(is_invisible(r"\vspace*{1em}", commands));
+        assert!(is_invisible(
+            r"\addcontentsline{toc}{section}{Intro}",
+            commands
+        ));
+        assert!(is_invisible(r"\linebreak[4]", commands));
+        assert!(!is_invisible(r"\LaTeX", commands));
+        assert!(!is_invisible(r"\labelfoo", commands));
+        assert!(!is_invisible(r"\label{foo} bar", commands));
+        assert!(!is_invisible(r"\label{foo", commands));
+        assert!(!is_invisible("", commands));
+
+        let raw = |src: &str| json!({"t": "RawInline", "c": ["tex", src]});
+        let mut tree: Pandoc = serde_json::from_value(json!({
+            "pandoc-api-version": [1, 23, 1],
+            "meta": {},
+            "blocks": [{"t": "Para", "c": [raw(r"\LaTeX"), raw(r"\label{sec:intro}")]}]
+        }))
+        .unwrap();
+        let mut renderer = FragmentRenderer::new(config);
+        renderer.walk_and_create_final_node(&mut tree);
+        let types = renderer
+            .fragments
+            .iter()
+            .map(|fragment| &fragment.ty)
+            .collect::<Vec<_>>();
+        assert_eq!(types, [&FragmentType::RawInline, &FragmentType::DontShow]);
+    }
+
+    #[test]
+    fn metadata_after_dontshow() {
+        let math = |ty: &str, src: &str| json!({"t": "Math", "c": [{"t": ty}, src]});
+        let mut tree: Pandoc = serde_json::from_value(json!({
+            "pandoc-api-version": [1, 23, 1],
+            "meta": {
+                "title": {"t": "MetaInlines", "c": [math("InlineMath", r"\Res(f)")]}
+            },
+            "blocks": [
+                {"t": "Para", "c": [
+                    math("DisplayMath", r"%dontshow \DeclareMathOperator{\Res}{Res}")
+                ]},
+                {"t": "Para", "c": [math("InlineMath", r"\Res(g)")]}
+            ]
+        }))
+        .unwrap();
+        let mut renderer = FragmentRenderer::new(ConfigBuilder::new().unwrap().build().unwrap());
+        renderer.walk_and_create_final_node(&mut tree);
+        let fragments = renderer.fragments.iter().collect::<Vec<_>>();
+        assert_eq!(fragments.len(), 3);
+        assert_eq!(fragments[0].ty, FragmentType::DontShow);
+        assert_eq!(fragments[2].src, r"\Res(f)");
+        let (source, lines) = renderer.generate_latex_with_line_mappings(&fragments);
+        // Lines are numbered from 1.
+        let source = source.lines().collect::<Vec<_>>();
+        assert!(source[lines[0].start - 1].contains(r"\DeclareMathOperator{\Res}{Res}"));
+        assert!(source[lines[2].start - 1].contains(r"\Res(f)"));
+    }
+
+    #[test]
+    fn option_values() {
+        let config = ConfigBuilder::new()
+            .unwrap()
+            .set("invisible_commands", vec!["label", "hfill"])
+            .unwrap()
+            .set("strict", true)
+            .unwrap()
+            .set("optimizer.eps", 0.01)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(config.invisible_commands, ["label", "hfill"]);
+        assert!(config.strict);
+        assert_eq!(config.optimizer.eps, 0.01);
+    }
+}
@@ -0,0 +1,165 @@
+// A minimal decoder for the .lzma (LZMA-alone) format, following the reference decoder in the LZMA
+// SDK (LzmaSpec.cpp). It exposes the subset of the API of lzma-d-min.js used by the decompressor
+// script, so that it can be bundled instead of loading lzma-d-min.js from a CDN.
+var LZMA = (function () {
+  function probs(n) {
+    return new Uint16Array(n).fill(1024);
+  }
+  function decode(d) {
+    var p = 0, i;
+    var props = d[p++], lc = props % 9, lp = ((props / 9) | 0) % 5, pb = (props / 45) | 0;
+    p += 4; // Dictionary size, irrelevant as we keep the whole output around anyway.
+    var size = 0, known = false;
+    for (i = 0; i < 8; i++) {
+      if (d[p + i] != 255) known = true;
+      size += d[p + i] * Math.pow(256, i);
+    }
+    p += 8;
+    var out = new Uint8Array(known ? size : Math.max(d.length * 4, 65536)), pos = 0;
+    function put(b) {
+      if (pos == out.length) {
+        var grown = new Uint8Array(out.length * 2);
+        grown.set(out);
+        out = grown;
+      }
+      out[pos++] = b;
+    }
+
+    var range = 0xffffffff, code = 0;
+    for (p++, i = 0; i < 4; i++) code = ((code << 8) | d[p++]) >>> 0;
+    function normalize() {
+      if (range < 16777216) {
+        range = (range << 8) >>> 0;
+        code = ((code << 8) | d[p++]) >>> 0;
+      }
+    }
+    function bit(probs, i) {
+      var prob = probs[i], bound = (range >>> 11) * prob, b;
+      if (code < bound) {
+        range = bound;
+        probs[i] = prob + ((2048 - prob) >> 5);
+        b = 0;
+      } else {
+        range -= bound;
+        code -= bound;
+        probs[i] = prob - (prob >> 5);
+        b = 1;
+      }
+      normalize();
+      return b;
+    }
+    function direct(n) {
+      var r = 0;
+      while (n--) {
+        range >>>= 1;
+        var b = code >= range ? 1 : 0;
+        if (b) code -= range;
+        r = r * 2 + b;
+        normalize();
+      }
+      return r;
+    }
+    function tree(probs, offset, n) {
+      for (var m = 1, i = 0; i < n; i++) m = (m << 1) | bit(probs, offset + m);
+      return m - (1 << n);
+    }
+    function reverseTree(probs, offset, n) {
+      for (var m = 1, s = 0, i = 0; i < n; i++) {
+        var b = bit(probs, offset + m);
+        m = (m << 1) | b;
+        s |= b << i;
+      }
+      return s;
+    }
+    function lenProbs() {
+      return { choice: probs(2), low: probs(128), mid: probs(128), high: probs(256) };
+    }
+    function len(probs, posState) {
+      if (!bit(probs.choice, 0)) return tree(probs.low, posState << 3, 3);
+      if (!bit(probs.choice, 1)) return 8 + tree(probs.mid, posState << 3, 3);
+      return 16 + tree(probs.high, 0, 8);
+    }
+
+    var literal = probs(0x300 << (lc + lp));
+    var isMatch = probs(192), isRep = probs(12), isRepG0 = probs(12), isRepG1 = probs(12),
+      isRepG2 = probs(12), isRep0Long = probs(192);
+    var posSlot = probs(256), posDecoders = probs(115), align = probs(16);
+    var matchLen = lenProbs(), repLen = lenProbs();
+    var state = 0, rep0 = 0, rep1 = 0, rep2 = 0, rep3 = 0;
+    while (!known || pos < size) {
+      var posState = pos & ((1 << pb) - 1);
+      if (!bit(isMatch, (state << 4) + posState)) {
+        var prev = pos > 0 ? out[pos - 1] : 0;
+        var base = 0x300 * (((pos & ((1 << lp) - 1)) << lc) + (prev >> (8 - lc)));
+        var symbol = 1;
+        if (state >= 7) {
+          var matchByte = out[pos - rep0 - 1];
+          do {
+            var matchBit = (matchByte >> 7) & 1;
+            matchByte <<= 1;
+            var b = bit(literal, base + ((1 + matchBit) << 8) + symbol);
+            symbol = (symbol << 1) | b;
+            if (matchBit != b) break;
+          } while (symbol < 0x100);
+        }
+        while (symbol < 0x100) symbol = (symbol << 1) | bit(literal, base + symbol);
+        put(symbol - 0x100);
+        state = state < 4 ? 0 : state < 10 ? state - 3 : state - 6;
+        continue;
+      }
+      var l;
+      if (bit(isRep, state)) {
+        if (!bit(isRepG0, state)) {
+          if (!bit(isRep0Long, (state << 4) + posState)) {
+            state = state < 7 ? 9 : 11;
+            put(out[pos - rep0 - 1]);
+            continue;
+          }
+        } else {
+          var dist;
+          if (!bit(isRepG1, state)) {
+            dist = rep1;
+          } else {
+            if (!bit(isRepG2, state)) {
+              dist = rep2;
+            } else {
+              dist = rep3;
+              rep3 = rep2;
+            }
+            rep2 = rep1;
+          }
+          rep1 = rep0;
+          rep0 = dist;
+        }
+        l = len(repLen, posState);
+        state = state < 7 ? 8 : 11;
+      } else {
+        rep3 = rep2;
+        rep2 = rep1;
+        rep1 = rep0;
+        l = len(matchLen, posState);
+        state = state < 7 ? 7 : 10;
+        var slot = tree(posSlot, (l < 3 ? l : 3) << 6, 6);
+        if (slot < 4) {
+          rep0 = slot;
+        } else {
+          var numDirect = (slot >> 1) - 1;
+          rep0 = (2 | (slot & 1)) * Math.pow(2, numDirect);
+          if (slot < 14) {
+            rep0 += reverseTree(posDecoders, rep0 - slot, numDirect);
+          } else {
+            rep0 += direct(numDirect - 4) * 16 + reverseTree(align, 0, 4);
+          }
+        }
+        if (rep0 == 0xffffffff) break; // End marker.
+      }
+      for (l += 2; l > 0 && (!known || pos < size); l--) put(out[pos - rep0 - 1]);
+    }
+    return out.subarray(0, pos);
+  }
+  return {
+    decompress: function (data, callback) {
+      callback(decode(data));
+    },
+  };
+})();
@@ -1,784 +1,372 @@
-use anyhow::{bail, Context, Result};
-use bytesize::ByteSize;
-use indoc::formatdoc;
-use serde_json::{json, Value};
 use std::{
-    borrow::Cow,
-    collections::{hash_map::DefaultHasher, BTreeMap, HashSet},
-    fs::File,
-    hash::{Hash, Hasher},
-    io::{stdin, stdout, Cursor, Read, Write},
-    ops::Range,
-    path::Path,
+    env, fs,
+    io::{self, stdin, stdout, Read, Write},
     process::Command,
-    rc::Rc,
     str::FromStr,
     time::Instant,
-    vec,
 };
-use tempfile::TempDir;
-use xz2::{read::XzEncoder, stream::LzmaOptions};
-
-use crate::config::{Config, TemplateConfig};
-use crate::synctex::Scanner;
 
-mod config;
-mod svg_optimize;
-mod svg_utils;
-mod synctex;
+use anyhow::{bail, Context, Result};
+use indoc::indoc;
+use just_latex::{Config, ConfigBuilder, FragmentKind};
+use serde_json::Value;
+
+const USAGE: &str = indoc! {"
+    A Pandoc filter rendering LaTeX fragments with an actual LaTeX engine.
+
+    Usage:
+        just-latex [<format>]                 Run as a Pandoc filter (the default)
+        just-latex render <input.json> [<output.json>] [--to <format>]
+                                              Render a Pandoc JSON AST from a file
+        just-latex check [<input.json>]       Check the configuration and the TeX toolchain
+        just-latex doctor [<input.json>]      Test rendering in every mode and report what works
+        just-latex print-config [<input.json>]
+                                              Print the effective configuration as TOML
+        just-latex clean [<input.json>]       Remove intermediate files from the output folder
+        just-latex --help | --version
+
+    The configuration is read from jlconfig.toml and, if a document is given, its metadata.
+"};
 
 fn main() -> Result<()> {
+    // Progress messages of the library, e.g. about the cache, go to stderr unless RUST_LOG says
+    // otherwise. They are printed without any decoration, as the filter always did.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format(|buf, record| writeln!(buf, "{}", record.args()))
+        .init();
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    match args.as_slice() {
+        ["-h" | "--help" | "help", ..] => println!("{}", USAGE),
+        ["-V" | "--version", ..] => println!("just-latex {}", env!("CARGO_PKG_VERSION")),
+        ["render", rest @ ..] => render(rest)?,
+        ["check", rest @ ..] => check(&load_config(rest)?)?,
+        ["doctor", rest @ ..] => doctor(&load_config(rest)?)?,
+        ["print-config", rest @ ..] => print_config(&load_config(rest)?)?,
+        ["clean", rest @ ..] => clean(&load_config(rest)?)?,
+        // Pandoc passes the target format as the first argument to filters.
+        [] => filter("html")?,
+        [path] if looks_like_path(path) => bail!(
+            "{} looks like a file rather than a Pandoc format; use `just-latex render {}` to \
+            render a document, see just-latex --help",
+            path,
+            path
+        ),
+        [format] if !format.starts_with('-') => filter(format)?,
+        _ => bail!("invalid arguments, see just-latex --help"),
+    }
+    Ok(())
+}
+
+/// Runs as a Pandoc filter, reading the document from stdin and writing it back to stdout.
+fn filter(format: &str) -> Result<()> {
     let mut buffer = String::new();
     let _ = stdin().read_to_string(&mut buffer)?;
     let mut tree = Value::from_str(&buffer)?;
     let config = Config::load(&tree)?;
-    config.sanity_check()?;
-    FragmentRenderer::new(config).render_with_latex(&mut tree)?;
+    process(&mut tree, &config, format)?;
     let output = serde_json::to_vec(&tree)?;
     stdout().write_all(&output)?;
     Ok(())
 }
 
-#[derive(Debug)]
-struct FragmentRenderer<'a> {
-    config: Config,
-    fragments: Vec<Fragment<'a>>,
-}
-
-#[derive(Debug)]
-struct Fragment<'a> {
-    ty: FragmentType,
-    src: String,
-    refs: Vec<FragmentNodeRef<'a>>,
+fn process(tree: &mut Value, config: &Config, format: &str) -> Result<()> {
+    if is_latex_format(format) {
+        just_latex::prepare_for_latex(tree, config)
+    } else {
+        let report = just_latex::render(tree, config)?;
+        for warning in report.warnings.iter() {
+            eprintln!("latex warning {}", warning);
+        }
+        for (src, error) in report.failed.iter() {
+            eprintln!("warning: failed to render fragment `{}`:", src);
+            for line in error.lines() {
+                eprintln!("    {}", line);
+            }
+        }
+        if !report.failed.is_empty() {
+            eprintln!(
+                "{} fragment(s) failed to render and have been replaced by placeholders",
+                report.failed.len()
+            );
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug)]
-enum FragmentNodeRef<'a> {
-    Inline(&'a mut Value),
-    Block(&'a mut Value),
+/// Returns whether an argument is a path given by mistake instead of the format Pandoc passes.
+/// Format names consist of letters, digits, `_` and extensions such as `+raw_tex`.
+fn looks_like_path(arg: &str) -> bool {
+    arg.contains(['/', '\\', '.'])
 }
 
-#[derive(Debug)]
-enum FragmentType {
-    /// For ordinary inline maths.
-    InlineMath(Style),
-    /// For display maths.
-    DisplayMath,
-    /// These will be included in the .tex file without being surrounded by "{}".
-    RawBlock,
-    /// For display maths starting with %dontshow. They are included in the tex files but not shown.
-    /// Use them for macro definitions.
-    DontShow,
+/// Returns whether Pandoc is producing LaTeX (or a PDF through LaTeX), in which case maths need not
+/// be rendered by us.
+fn is_latex_format(format: &str) -> bool {
+    // Formats may come with extensions, e.g. "latex+raw_tex".
+    let format = format.split(['+', '-']).next().unwrap_or_default();
+    format == "latex" || format == "beamer"
 }
 
-// On style: technically the correct way to handle styles is to handle find a set or orthogonal
-// properties and make a product type out of it. But this is not extensible in a sense that
-// orthogonality might be broken as new styles are considered. So instead we here just consider
-// style to be an ordered list of style elements. The problem with this approach, however, is that
-// it becomes difficult to compare equivalence of styles. Is Strong then Emph equivalent to Emph
-// then Strong? Is nested Quote equivalent to single Quote? Equivalence of styles is necessary to
-// deduplicate fragments and reduce size of our output. Of course for sane inputs this wouldn't be
-// a problem.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum StyleElement {
-    Header(u64),
-    Quote,
-    Strong,
-    Emph,
+fn read_document(path: &str) -> Result<Value> {
+    let content = fs::read_to_string(path).with_context(|| format!("reading {}", path))?;
+    Value::from_str(&content).with_context(|| format!("parsing {}", path))
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-/// Inline math style.
-enum Style {
-    Plain,
-    Fancy { base: Rc<Style>, this: StyleElement },
+/// Loads the configuration, including the metadata of the document if one is given.
+fn load_config(args: &[&str]) -> Result<Config> {
+    match args {
+        [] => ConfigBuilder::new()?.default_files()?.build(),
+        [path] => Config::load(&read_document(path)?),
+        _ => bail!("invalid arguments, see just-latex --help"),
+    }
 }
 
-impl Style {
-    fn push(self, new: StyleElement) -> Self {
-        Self::Fancy {
-            base: Rc::new(self),
-            this: new,
+fn render(args: &[&str]) -> Result<()> {
+    let (mut input, mut output, mut format) = (None, None, "html");
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "-t" | "--to" => format = args.next().context("missing format after --to")?,
+            _ if input.is_none() => input = Some(*arg),
+            _ if output.is_none() => output = Some(*arg),
+            _ => bail!("invalid arguments, see just-latex --help"),
         }
     }
-
-    fn template(&self, config: &TemplateConfig) -> String {
-        match self {
-            Style::Plain => config.inline_math_inner.clone(),
-            Style::Fancy { base, this } => {
-                let base_template = base.template(config);
-                let this_template = match this {
-                    StyleElement::Header(level) => &config.header[*level as usize - 1],
-                    StyleElement::Quote => &config.quote,
-                    StyleElement::Strong => &config.strong,
-                    StyleElement::Emph => &config.emph,
-                };
-                this_template.replace(&config.placeholder, &base_template)
-            }
-        }
+    let input = input.context("missing input file, see just-latex --help")?;
+    let mut tree = read_document(input)?;
+    let config = Config::load(&tree)?;
+    process(&mut tree, &config, format)?;
+    let json = serde_json::to_vec(&tree)?;
+    match output {
+        Some(path) => fs::write(path, json).with_context(|| format!("writing {}", path))?,
+        None => stdout().write_all(&json)?,
     }
+    Ok(())
 }
 
-impl<'a> FragmentRenderer<'a> {
-    fn new(config: Config) -> Self {
-        Self {
-            config,
-            fragments: vec![],
+/// Checks that the configuration is valid (which [`load_config`] already did) and that the
+/// configured executables can be run.
+fn check(config: &Config) -> Result<()> {
+    println!("configuration: ok");
+    let mut ok = true;
+    match Command::new(&config.latex).arg("--help").output() {
+        Ok(help) if String::from_utf8_lossy(&help.stdout).contains("-synctex") => {
+            println!("{}: ok", config.latex);
         }
-    }
-
-    fn add_fragment(&mut self, ty: FragmentType, src: &str, node_ref: FragmentNodeRef<'a>) {
-        match ty {
-            // Inline fragments are often duplicates of previous ones encountered.
-            // Caveat: if inline fragments contain expansions of macro with side effect (which is
-            // rather unlikely), then this could cause trouble!
-            FragmentType::InlineMath(ref styles) => {
-                let src = src.trim();
-                for item in self.fragments.iter_mut() {
-                    match item.ty {
-                        FragmentType::InlineMath(ref rstyles)
-                            if item.src == src && styles == rstyles =>
-                        {
-                            item.refs.push(node_ref);
-                            return;
-                        }
-                        _ => continue,
-                    }
-                }
-                self.fragments.push(Fragment {
-                    ty,
-                    src: src.into(),
-                    refs: vec![node_ref],
-                });
-            }
-            _ => {
-                self.fragments.push(Fragment {
-                    ty,
-                    src: src.trim().into(),
-                    refs: vec![node_ref],
-                });
-            }
+        Ok(_) => {
+            println!("{}: does not support -synctex", config.latex);
+            ok = false;
         }
-    }
-
-    fn generate_latex_with_line_mappings(&self) -> (String, Vec<Range<usize>>) {
-        let mut lines: Vec<Range<usize>> = vec![];
-        let mut output = String::new();
-        let preamble_trimmed = self.config.preamble.trim_end();
-        output.push_str(preamble_trimmed);
-        output.push('\n');
-        let mut current_line = preamble_trimmed.lines().count() + 1;
-        for item in self.fragments.iter() {
-            let template_config = &self.config.template;
-            let expanded = match &item.ty {
-                FragmentType::InlineMath(style) => {
-                    let inner = style
-                        .template(template_config)
-                        .replace(&template_config.placeholder, &item.src);
-                    self.config
-                        .template
-                        .inline_math
-                        .replace(&template_config.placeholder, &inner)
-                }
-                FragmentType::DisplayMath => template_config
-                    .display_math
-                    .replace(&template_config.placeholder, &item.src),
-                FragmentType::RawBlock | FragmentType::DontShow => item.src.clone(),
-            };
-            let expanded = expanded.trim_end();
-            let start_line = current_line;
-            output.push_str(expanded);
-            current_line += expanded.lines().count();
-            lines.push(start_line..current_line);
-            output.push_str("\n\n");
-            current_line += 1;
+        Err(e) => {
+            println!("{}: cannot run: {}", config.latex, e);
+            ok = false;
         }
-        output.push_str(&self.config.postamble);
-        (output, lines)
     }
-
-    /// Scans and modifies the tree in-place, replacing all inline and display maths with rendered
-    /// SVGs.
-    pub fn render_with_latex(mut self, tree: &'a mut Value) -> Result<()> {
-        let final_node = self.walk_and_create_final_node(tree)?;
-
-        if self.fragments.is_empty() {
-            // Make the final node a dummy.
-            *final_node = json!({"t": "RawBlock", "c": ["html", ""]});
-            return Ok(());
+    match Command::new(&config.dvisvgm).arg("--version").output() {
+        Ok(version) if version.status.success() => println!("{}: ok", config.dvisvgm),
+        Ok(_) => {
+            println!("{}: --version failed", config.dvisvgm);
+            ok = false;
         }
-
-        // In TeX 1 in = 72.72 pt = 72 bp, while in SVG 1 in = 72 pt.
-        // Due to different definitions of pt we need a small scaling factor here.
-        // See https://github.com/mgieseki/dvisvgm/issues/185
-        const TEX2SVG_SCALING: f64 = 72.0 / 72.27;
-
-        let (source_str, lines) = self.generate_latex_with_line_mappings();
-        let working_dir = match self.config.output_folder {
-            Some(_) => None,
-            None => Some(TempDir::new()?),
-        };
-        let working_path = match &working_dir {
-            Some(working_dir) => working_dir.path().to_path_buf(),
-            None => Path::new(&self.config.output_folder.unwrap()).to_path_buf(),
+        Err(e) => {
+            println!("{}: cannot run: {}", config.dvisvgm, e);
+            ok = false;
         }
-        .canonicalize()?;
-        let source_path = working_path.join("source.tex");
+    }
+    if !ok {
+        bail!("some checks failed");
+    }
+    Ok(())
+}
 
-        // eprintln!("{}", source_str);
-        {
-            let mut source = File::create(&source_path)?;
-            source.write_all(source_str.as_bytes())?;
-        }
-        let mut latex_command = Command::new(self.config.latex);
-        if self.config.mode == "dvi" {
-            latex_command.arg("-output-format=dvi");
-        } else if self.config.mode == "xdv" {
-            latex_command.arg("--no-pdf");
+/// Reports the versions of the engines and of dvisvgm along with its optional features, then renders
+/// a small test document through the full pipeline in every mode.
+fn doctor(config: &Config) -> Result<()> {
+    println!(
+        "configured: mode = {}, latex = {}",
+        config.mode, config.latex
+    );
+    println!();
+    let mut engines = vec![config.latex.as_str()];
+    engines.extend(
+        ["pdflatex", "xelatex"]
+            .into_iter()
+            .filter(|engine| *engine != config.latex),
+    );
+    for engine in engines {
+        match first_line_of(engine, "--version") {
+            Ok(version) => println!("{}: {}", engine, version),
+            Err(e) => println!("{}: cannot run: {}", engine, e),
         }
-        let pdf_path = working_path.join(if self.config.mode == "pdf" {
-            "source.pdf"
-        } else if self.config.mode == "dvi" {
-            "source.dvi"
-        } else {
-            "source.xdv"
-        });
-        let latex_command = latex_command
-            .args([
-                "-synctex=-1",
-                "-interaction=nonstopmode",
-                source_path.to_str().unwrap(),
-            ])
-            .current_dir(&working_path)
-            .output()?;
-        if !latex_command.status.success() {
-            let error_message = String::from_utf8_lossy(&latex_command.stdout);
-            eprintln!("latex error: {error_message}");
-            bail!("fail to run latex: {error_message}",);
-        }
-
-        let mut dvisvgm_command = Command::new(self.config.dvisvgm);
-        if self.config.mode == "pdf" {
-            dvisvgm_command.arg("--pdf");
-        } else {
-            dvisvgm_command.arg("--font-format=ttf");
+    }
+    match first_line_of(&config.dvisvgm, "--version") {
+        Ok(version) => println!("{}: {}", config.dvisvgm, version),
+        Err(e) => println!("{}: cannot run: {}", config.dvisvgm, e),
+    }
+    let features = dvisvgm_features(&config.dvisvgm).unwrap_or_default();
+    for (name, version) in &features {
+        println!("    {}: {}", name, version);
+    }
+    // dvisvgm lists optional libraries and tools it found with their versions, and those it did
+    // not find with a dash or "not available".
+    let has = |name: &str| {
+        features.iter().any(|(feature, version)| {
+            feature.eq_ignore_ascii_case(name) && version.starts_with(|c: char| c.is_ascii_digit())
+        })
+    };
+    if !features.is_empty() {
+        println!(
+            "    PDF input (needs mutool or Ghostscript): {}",
+            if has("mutool") || has("Ghostscript") {
+                "available"
+            } else {
+                "missing"
+            }
+        );
+    }
+    println!();
+
+    // The same test document for every mode, covering inline and display maths and raw TeX.
+    let fragments = [
+        (FragmentKind::DontShow, r"\newcommand{\R}{\mathbb{R}}"),
+        (FragmentKind::InlineMath, r"f\colon \R \to \R"),
+        (
+            FragmentKind::DisplayMath,
+            r"\sum_{k=1}^n k = \frac{n(n+1)}{2}",
+        ),
+        (FragmentKind::RawInline, r"\LaTeX"),
+    ];
+    let mut configured_works = false;
+    for (mode, default_engine) in [("pdf", "pdflatex"), ("dvi", "pdflatex"), ("xdv", "xelatex")] {
+        let mut test_config = config.clone();
+        if mode != config.mode {
+            test_config.latex = default_engine.into();
         }
-        let dvisvgm_command = dvisvgm_command
-            .args([
-                "--stdout",
-                "--relative", // Empirically reduces SVG size.
-                "--page=1-",  // Convert all pages.
-                pdf_path.to_str().unwrap(),
-            ])
-            .current_dir(&working_path)
-            .output()?;
-        if !dvisvgm_command.status.success() {
-            bail!(
-                "fail to run dvisvgm: {}",
-                String::from_utf8_lossy(&dvisvgm_command.stderr).trim()
-            );
+        test_config.mode = mode.into();
+        if mode != "pdf" && test_config.positioning == "preview" {
+            // The preview package only crops pages in PDF mode.
+            test_config.positioning = "synctex".into();
         }
-        // Split svgs because we might have multiple pages.
-        let svg_data = svg_utils::split_svgs(&dvisvgm_command.stdout)?;
-        let svgs = svg_data
-            .iter()
-            .map(|svg_data| svg_utils::parse_to_tree(svg_data))
-            .collect::<Result<Vec<_>, _>>()?;
-
-        // A unique class name for each svg is important because HTMLs from multiple posts
-        // may be put together in the home page of a blog. Then the decompressing code of each page
-        // starts a race, each trying to modify every fragment image.
-        let svg_class_names = svg_data
-            .iter()
-            .map(|svg| {
-                let mut hasher = DefaultHasher::new();
-                svg.hash(&mut hasher);
-                let hash = hasher.finish();
-                format!("jl-{}", base64::encode(hash.to_be_bytes()))
-            })
-            .collect::<Vec<_>>();
-
-        let bboxes = svgs
-            .iter()
-            .map(svg_utils::paths_to_bboxes)
-            .collect::<Vec<_>>();
-        let scanner = Scanner::new(pdf_path, &working_path);
-        let mut seen_boxes = HashSet::new();
-
-        for (item, line_range) in self.fragments.iter_mut().zip(lines) {
-            if let FragmentType::DontShow = item.ty {
-                // Skip dont shows.
-                for node in item.refs.iter_mut() {
-                    match node {
-                        FragmentNodeRef::Inline(node) => {
-                            **node = json!({"t": "RawInline", "c": ["html", ""]})
-                        }
-                        FragmentNodeRef::Block(node) => {
-                            **node = json!({"t": "RawBlock", "c": ["html", ""]});
-                        }
-                    }
-                }
-                continue;
-            }
-
-            #[derive(Clone, Debug)]
-            struct Region {
-                x_range: (f64, f64),
-                y_range: (f64, f64),
-                baseline: f64,
-                baseline_width: f64,
-            }
-
-            let mut regions: BTreeMap<u32, Region> = BTreeMap::new();
-
-            for line in line_range {
-                for tb in scanner.query(line) {
-                    let area = tb.width * (tb.height + tb.depth);
-                    if area.into_inner() <= 1e-6 {
-                        // Skip zero-area boxes. They may be generated by the TeX page breaker and
-                        // do not actually correspond to anything in our source file. Also they
-                        // wouldn't contribute to updating the region of the page anyways.
-                        continue;
-                    }
-                    if seen_boxes.contains(&tb) {
-                        // Continue if we have seen this box -- then probably that's SyncTeX's
-                        // fault
-                        continue;
-                    }
-                    seen_boxes.insert(tb.clone());
-
-                    let (x_low, x_high) = (tb.h.into_inner(), (tb.h + tb.width).into_inner());
-                    let (y_low, y_high) = (
-                        (tb.v - tb.height).into_inner(),
-                        (tb.v + tb.depth).into_inner(),
-                    );
-                    regions
-                        .entry(tb.page)
-                        .and_modify(|r| {
-                            r.x_range = (r.x_range.0.min(x_low), r.x_range.1.max(x_high));
-                            r.y_range = (r.y_range.0.min(y_low), r.y_range.1.max(y_high));
-                            if tb.width.into_inner() > r.baseline_width {
-                                r.baseline_width = tb.width.into_inner();
-                                r.baseline = tb.v.into_inner();
-                            }
-                        })
-                        .or_insert_with(|| Region {
-                            x_range: (x_low, x_high),
-                            y_range: (y_low, y_high),
-                            baseline: tb.v.into(),
-                            baseline_width: tb.width.into(),
-                        });
+        test_config.optimizer.enabled &= mode == "pdf";
+        // Rendering standalone SVGs does not depend on the output settings, but the checks do.
+        test_config.output = "script".into();
+        test_config.asset_dir = None;
+        test_config.cache_dir = None;
+        test_config.output_folder = None;
+        test_config.on_error = "fail".into();
+        test_config.strict = false;
+        let start = Instant::now();
+        let result = just_latex::render_fragments(&fragments, &test_config);
+        let label = format!("mode = {} with {}", mode, test_config.latex);
+        match result {
+            Ok(rendered) => {
+                let parts = rendered
+                    .iter()
+                    .flat_map(|fragment| &fragment.parts)
+                    .collect::<Vec<_>>();
+                let visible = fragments
+                    .iter()
+                    .filter(|(kind, _)| *kind != FragmentKind::DontShow)
+                    .count();
+                if parts.len() < visible
+                    || parts
+                        .iter()
+                        .any(|part| part.width <= 0.0 || part.height <= 0.0)
+                {
+                    println!("{}: rendered, but some fragments are empty", label);
+                    continue;
                 }
-            }
-
-            if regions.is_empty() {
-                bail!("no boxes for {}", item.src);
-            }
-            if matches!(item.ty, FragmentType::InlineMath(_)) && regions.len() > 1 {
-                bail!(
-                    "inline fragments '{}' spans multiple pages {:?} (did you disable page numbering?)",
-                    item.src,
-                    regions.keys().collect::<Vec<_>>()
-                );
-            }
-
-            let mut imgs = vec![];
-            for (
-                page,
-                Region {
-                    mut x_range,
-                    mut y_range,
-                    mut baseline,
-                    ..
-                },
-            ) in regions.into_iter()
-            {
-                let svg_idx = page as usize - 1;
-                // For whatever reason, the coordinate system of SVGs resulting from PDF
-                // conversion is translated.
-                let (x_base, y_base) = if self.config.mode == "pdf" {
-                    let view_box = &svgs[svg_idx].svg_node().view_box.rect;
-                    (view_box.left(), view_box.top())
+                let fonts = if parts.iter().any(|part| part.svg.contains("@font-face")) {
+                    "text as embedded TTF fonts"
                 } else {
-                    (0.0, 0.0)
+                    "text as paths"
                 };
-                // Convert everything from TeX coordinates to SVG coordinates.
-                y_range = (
-                    y_range.0 * TEX2SVG_SCALING + y_base,
-                    y_range.1 * TEX2SVG_SCALING + y_base,
+                println!(
+                    "{}: works ({}, {:.2}s)",
+                    label,
+                    fonts,
+                    start.elapsed().as_secs_f64()
                 );
-                x_range = svg_utils::x_range_for_y_range(
-                    &bboxes[svg_idx],
-                    y_range.0,
-                    y_range.1,
-                    self.config.y_range_tol,
-                    self.config.x_range_margin,
-                )
-                .unwrap_or((
-                    x_range.0 * TEX2SVG_SCALING + x_base,
-                    x_range.1 * TEX2SVG_SCALING + x_base,
-                ));
-                baseline = baseline * TEX2SVG_SCALING + y_base;
-
-                if let FragmentType::DisplayMath | FragmentType::RawBlock = item.ty {
-                    y_range = svg_utils::refine_y_range(
-                        &bboxes[svg_idx],
-                        y_range.0,
-                        y_range.1,
-                        self.config.y_range_tol,
-                    );
-                }
-                y_range.0 -= self.config.y_range_margin;
-                y_range.1 += self.config.y_range_margin;
-
-                let depth = match item.ty {
-                    FragmentType::InlineMath(_) => y_range.1 - baseline,
-                    FragmentType::DisplayMath | FragmentType::RawBlock => 0.0,
-                    FragmentType::DontShow => unreachable!(),
-                };
-                let extra_style = match item.ty {
-                    FragmentType::InlineMath(_) => format!(
-                        "top:{depth:.2}pt;margin-top:{neg_depth:.2}pt;position:relative;{extra_style}",
-                        depth = depth - self.config.baseline_rise,
-                        neg_depth = self.config.baseline_rise - depth,
-                        extra_style = self.config.extra_style_inline
-                    ),
-                    FragmentType::DisplayMath | FragmentType::RawBlock => {
-                        self.config.extra_style_display.clone()
-                    }
-                    FragmentType::DontShow => unreachable!(),
-                };
-                imgs.push(formatdoc!(
-                    r##"<img src="#svgView(viewBox({x:.2},{y:.2},{width:.2},{height:.2}))"
-                         class="{class_name} jl-{ty}" alt = "{alt}"
-                         style="width:{width:.2}pt;height:{height:.2}pt;
-                         display:inline;{extra_style}">"##,
-                    x = x_range.0,
-                    y = y_range.0,
-                    width = x_range.1 - x_range.0,
-                    height = y_range.1 - y_range.0,
-                    ty = if let FragmentType::InlineMath(_) = item.ty {
-                        "inline"
-                    } else {
-                        "display"
-                    },
-                    class_name = svg_class_names[svg_idx],
-                    alt = html_escape::encode_text(&item.src),
-                    extra_style = extra_style
-                ));
-            }
-            let html = match item.ty {
-                FragmentType::InlineMath(_) => imgs.join(""),
-                FragmentType::DisplayMath | FragmentType::RawBlock => {
-                    format!(
-                        r#"<div class="jl-display-div" style="text-align:center;">{}</div>"#,
-                        imgs.join("<br>")
-                    )
-                }
-                FragmentType::DontShow => unreachable!(),
-            };
-            for node in item.refs.iter_mut() {
-                match node {
-                    FragmentNodeRef::Inline(node) => {
-                        **node = json!({"t": "RawInline", "c": ["html", &html]});
-                    }
-                    FragmentNodeRef::Block(node) => {
-                        **node = json!({"t": "RawBlock", "c": ["html", &html]});
-                    }
-                }
+                configured_works |= mode == config.mode;
             }
+            Err(e) => println!("{}: fails: {:#}", label, e),
         }
-
-        let lzma_options = LzmaOptions::new_preset(9)?;
-        let mut decompress_script = String::new();
-        let svg_data = if self.config.optimizer.enabled {
-            svgs.iter()
-                .map(|tree| -> Result<Cow<[u8]>> {
-                    Ok(Cow::Owned(svg_optimize::optimize(
-                        tree,
-                        self.config.optimizer.eps,
-                    )?))
-                })
-                .collect::<Result<Vec<_>, _>>()?
-        } else {
-            svg_data.iter().map(|data| Cow::Borrowed(*data)).collect()
-        };
-        for (i, (svg, class_name)) in svg_data.into_iter().zip(svg_class_names).enumerate() {
-            let start = Instant::now();
-            let original_size = svg.len();
-            let mut svg_compressor = XzEncoder::new_stream(
-                Cursor::new(svg),
-                xz2::stream::Stream::new_lzma_encoder(&lzma_options)?,
-            );
-            let mut svg_compressed = vec![];
-            svg_compressor.read_to_end(&mut svg_compressed)?;
-            let svg_encoded = base64::encode(svg_compressed);
-            decompress_script.push_str(&formatdoc!(
-                r##"
-                    var w{page}=new Worker(s);
-                    w{page}.onmessage=f("{class_name}");
-                    w{page}.postMessage("{svg}");
-                "##,
-                page = i + 1,
-                svg = svg_encoded,
-                class_name = class_name
-            ));
-
-            eprintln!(
-                "SVG for page {} compressed from {} down to {} (base64 encoded) in {}s",
-                i + 1,
-                ByteSize::b(original_size as u64),
-                ByteSize::b(svg_encoded.len() as u64),
-                start.elapsed().as_secs_f64()
-            );
-        }
-
-        let final_code = formatdoc!(
-            r##"
-            <script {extra_attribs}>
-                (function(){{
-                    var s=URL.createObjectURL(new Blob(['"function"==typeof importScripts&&(importScripts("{lzma_js_path}"),onmessage=function(a){{LZMA.decompress(Uint8Array.from(atob(a.data),function(a){{return a.charCodeAt(0)}}),function(a,b){{postMessage(a)}})}})'], {{type: "text/javascript"}}));
-                    var f=function(a){{return function(e){{for(var f=URL.createObjectURL(new Blob([typeof e.data==="string"?e.data:new Uint8Array(e.data)],{{type:"image/svg+xml"}})),c=document.getElementsByClassName(a),b=0;b<c.length;b++){{var d=c[b].src.indexOf("#");-1!=d&&(c[b].src=f+c[b].src.substring(d))}}}}}};
-                    {decompress_script}
-                }}());
-            </script>
-            "##,
-            extra_attribs = self.config.script_extra_attributes,
-            lzma_js_path = self.config.lzma_js_path,
-            decompress_script = decompress_script
-        );
-        *final_node = json!({
-            "t": "RawBlock",
-            "c": [
-                "html",
-                final_code,
-            ]
-        });
-        Ok(())
-    }
-
-    // Below are a lot of tree-walking methods.
-    // I wasn't aware of any good libraries for parsing Pandoc ASTs when I wrote all of these. And
-    // by the time I knew pandoc-ast or pandoc-types I realized I reinvented the wheels again.
-    // That said now that I think of it again, there's something JustLaTeX needs that pandoc-ast
-    // does not yet offer: after visiting every math node we need to keep a series of mut references
-    // to the math nodes so we can change them to inline svgs later. Pandoc-ast's MutVisitor traits
-    // does saves a ton of the boilerplates below but the trait methods do not have lifetime
-    // parameters, making it impossible to store references for future use safely. Hopefully this
-    // justifies a ton of unwieldly practices below...
-
-    /// Walks the tree and look for math nodes. Also creates and returns the reference to an empty
-    /// final node, which we will modify later. Due to the borrow checker this is the only place we
-    /// can add stuff to the tree. If we just call self.walk_blocks(&mut tree["blocks"], "Document")
-    /// in render_with_latex() and try to modify tree["blocks"] afterwards, the borrow checker will
-    /// complain.
-    fn walk_and_create_final_node(&mut self, tree: &'a mut Value) -> Result<&'a mut Value> {
-        let blocks = tree["blocks"]
-            .as_array_mut()
-            .context("reading [blocks] of the Document")?;
-        let last_idx = blocks.len();
-        blocks.push(json!({}));
-        let mut ret = None;
-        for (i, block) in blocks.iter_mut().enumerate() {
-            if i == last_idx {
-                ret = Some(block);
-            } else {
-                self.walk_block(block, Style::Plain)?;
-            }
-        }
-        Ok(ret.unwrap())
     }
-
-    fn walk_block(&mut self, value: &'a mut Value, style: Style) -> Result<()> {
-        match value["t"].as_str().context("reading type of Block")? {
-            "Para" => self.walk_inlines(&mut value["c"], "Para", style),
-            "Plain" => self.walk_inlines(&mut value["c"], "Plain", style),
-            "LineBlock" => self.walk_list_of_inlines(&mut value["c"], "LineBlock", style),
-            "Header" => {
-                let level = value["c"][0].as_u64().context("reading level of Header")?;
-                self.walk_inlines(
-                    &mut value["c"][2],
-                    "Header",
-                    style.push(StyleElement::Header(level)),
-                )?;
-                Ok(())
-            }
-            "BlockQuote" => self.walk_blocks(
-                &mut value["c"],
-                "BlockQuote",
-                style.push(StyleElement::Quote),
-            ),
-            "OrderedList" => self.walk_list_of_blocks(&mut value["c"][1], "OrderedList", style),
-            "BulletList" => self.walk_list_of_blocks(&mut value["c"], "BulletList", style),
-            "Div" => self.walk_list_of_blocks(&mut value["c"][1], "Div", style),
-            "RawBlock" => {
-                let c = &value["c"];
-                let format = c[0].as_str().context("reading format of RawBlock")?;
-                if format == "tex" {
-                    let text = String::from(c[1].as_str().context("reading source of RawBlock")?);
-                    self.add_fragment(
-                        if text.trim_start().starts_with("%dontshow") {
-                            FragmentType::DontShow
-                        } else {
-                            FragmentType::RawBlock
-                        },
-                        &text,
-                        FragmentNodeRef::Block(value),
-                    );
-                }
-                Ok(())
-            }
-            "Table" => {
-                for (i, content) in value["c"]
-                    .as_array_mut()
-                    .context("reading contents of Table")?
-                    .iter_mut()
-                    .enumerate()
-                // Circumvent the borrow checker ... isn't it nasty?
-                {
-                    match i {
-                        1 => {
-                            self.walk_blocks(&mut content[1], "Table.Caption", style.clone())?;
-                        }
-                        3 => {
-                            self.walk_rows(&mut content[1], "Table.TableHead", style.clone())?;
-                        }
-                        4 => {
-                            for table_body in content
-                                .as_array_mut()
-                                .context("reading Table.[TableBody]")?
-                            {
-                                for rows in table_body
-                                    .as_array_mut()
-                                    .context("reading content of Table.[TableBody]")?
-                                    .iter_mut()
-                                    .skip(2)
-                                {
-                                    self.walk_rows(rows, "Table.[TableBody].[Row]", style.clone())?;
-                                }
-                            }
-                        }
-                        5 => {
-                            self.walk_rows(&mut content[1], "Table.TableFoot", style.clone())?;
-                        }
-                        _ => {}
-                    }
-                }
-                Ok(())
-            }
-            _ => Ok(()),
-        }
+    if !configured_works {
+        bail!("the configured mode does not work");
     }
+    Ok(())
+}
 
-    fn walk_inline(&mut self, value: &'a mut Value, style: Style) -> Result<()> {
-        match value["t"].as_str().context("reading type of Inline")? {
-            "Math" => {
-                let c = &value["c"];
-                let ty = c[0]["t"].as_str().context("reading type of Math")?;
-                let text = String::from(c[1].as_str().context("reading source of Math")?);
-                let ty = match ty {
-                    // A better idea would be to use persistent list which avoids cloning and much
-                    // of the push-and-pop boilerplates below. But empirically style don't have
-                    // a lot of elements.
-                    "InlineMath" => FragmentType::InlineMath(style),
-                    "DisplayMath" => {
-                        let trimmed_text = text.trim_start();
-                        if trimmed_text.starts_with("%raw") {
-                            FragmentType::RawBlock
-                        } else if trimmed_text.starts_with("%dontshow") {
-                            FragmentType::DontShow
-                        } else {
-                            FragmentType::DisplayMath
-                        }
-                    }
-                    _ => bail!("unknown math type {}", ty),
-                };
-                self.add_fragment(ty, &text, FragmentNodeRef::Inline(value));
-                Ok(())
-            }
-            "Emph" => self.walk_inlines(&mut value["c"], "Emph", style.push(StyleElement::Emph)),
-            // TODO: render them differently in latex.
-            "Underline" => self.walk_inlines(&mut value["c"], "Underline", style),
-            "Strong" => {
-                self.walk_inlines(&mut value["c"], "Strong", style.push(StyleElement::Strong))
-            }
-            "Strikeout" => self.walk_inlines(&mut value["c"], "Strikeout", style),
-            "Link" => self.walk_inlines(&mut value["c"][1], "Link", style),
-            "Image" => self.walk_inlines(&mut value["c"][1], "Image", style),
-            _ => Ok(()),
-        }
-    }
+/// Runs a command and returns the first line of its output.
+fn first_line_of(program: &str, arg: &str) -> Result<String> {
+    let output = Command::new(program).arg(arg).output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.lines().next().unwrap_or_default().trim().to_string())
+}
 
-    fn walk_blocks(&mut self, value: &'a mut Value, parent: &str, style: Style) -> Result<()> {
-        for block in value
-            .as_array_mut()
-            .with_context(|| format!("reading {}.[Block]", parent))?
-            .iter_mut()
-        {
-            self.walk_block(block, style.clone())?;
-        }
-        Ok(())
-    }
+/// Parses the output of `dvisvgm --version=extended`, which lists the versions of the libraries and
+/// tools dvisvgm uses, e.g. `mutool:      1.23.0`, after a header and a line of dashes.
+fn dvisvgm_features(dvisvgm: &str) -> Result<Vec<(String, String)>> {
+    let output = Command::new(dvisvgm).arg("--version=extended").output()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout
+        .lines()
+        .skip(1)
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('-'))
+        .filter_map(|line| {
+            let line = line.trim();
+            let (name, version) = line
+                .split_once(':')
+                .or_else(|| line.split_once(char::is_whitespace))?;
+            Some((name.trim().to_string(), version.trim().to_string()))
+        })
+        .collect())
+}
 
-    fn walk_list_of_blocks(
-        &mut self,
-        value: &'a mut Value,
-        parent: &str,
-        style: Style,
-    ) -> Result<()> {
-        for blocks in value
-            .as_array_mut()
-            .with_context(|| format!("reading {}.[[Block]]", parent))?
-            .iter_mut()
-        {
-            self.walk_blocks(blocks, parent, style.clone())?;
-        }
-        Ok(())
-    }
+fn print_config(config: &Config) -> Result<()> {
+    // Serializing the struct directly fails because toml requires plain values to come before
+    // tables, while a generic TOML value is reordered as needed.
+    let value = toml::Value::try_from(config)?;
+    print!("{}", toml::to_string_pretty(&value)?);
+    Ok(())
+}
 
-    fn walk_inlines(&mut self, value: &'a mut Value, parent: &str, style: Style) -> Result<()> {
-        for inline in value
-            .as_array_mut()
-            .with_context(|| format!("reading {}.[Inline]", parent))?
-            .iter_mut()
-        {
-            self.walk_inline(inline, style.clone())?;
+fn clean(config: &Config) -> Result<()> {
+    let folder = match &config.output_folder {
+        Some(folder) => folder,
+        None => {
+            println!("no output_folder configured, nothing to clean");
+            return Ok(());
         }
-        Ok(())
-    }
-
-    fn walk_list_of_inlines(
-        &mut self,
-        value: &'a mut Value,
-        parent: &str,
-        style: Style,
-    ) -> Result<()> {
-        for inlines in value
-            .as_array_mut()
-            .with_context(|| format!("reading {}.[[Inline]]", parent))?
-            .iter_mut()
-        {
-            self.walk_inlines(inlines, parent, style.clone())?;
+    };
+    let entries = match fs::read_dir(folder) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", folder)),
+    };
+    // The job is always named "source", so everything the engine and just-latex write is named
+    // source.*, including the .jlpos file. Anything else is left alone, as the folder may well be
+    // shared with other files.
+    let (mut removed, mut kept) = (0, 0);
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        if entry.file_type()?.is_file() && name.to_string_lossy().starts_with("source.") {
+            fs::remove_file(entry.path())
+                .with_context(|| format!("removing {}", entry.path().display()))?;
+            removed += 1;
+        } else {
+            kept += 1;
         }
-        Ok(())
     }
-
-    fn walk_rows(&mut self, value: &'a mut Value, parent: &str, style: Style) -> Result<()> {
-        for row in value
-            .as_array_mut()
-            .with_context(|| format!("reading {}.[Row]", parent))?
-        {
-            for cell in row[1]
-                .as_array_mut()
-                .with_context(|| format!("reading {}.[Row].[Cell]", parent))?
-            {
-                self.walk_blocks(
-                    &mut cell[4],
-                    "[Cell] of Row of TableHead of Table",
-                    style.clone(),
-                )?;
-            }
-        }
-        Ok(())
+    println!("removed {} files from {}", removed, folder);
+    if kept > 0 {
+        println!(
+            "left {} other entries, which just-latex did not write",
+            kept
+        );
     }
+    Ok(())
 }
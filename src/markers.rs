@@ -0,0 +1,145 @@
+//! Positioning of fragments through markers, an alternative to SyncTeX.
+//!
+//! Each fragment is typeset into a box, and a `\pdfsavepos` marker placed right before the box
+//! writes the position of its reference point on the page along with its width, height and depth
+//! to `<jobname>.jlpos` when the page is shipped out. A line of the file looks like
+//!
+//! ```text
+//! <fragment>,<page>,<x>,<y>,<page height>,<width>,<height>,<depth>
+//! ```
+//!
+//! where all lengths are in sp and `y` is measured from the bottom of the page, as is usual for
+//! `\pdflastypos`. The page is counted by shipouts rather than taken from `\c@page`, which the
+//! document may reset or set to anything through `\setcounter{page}` or `\pagenumbering`.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use indoc::indoc;
+
+/// Name of the file the markers are written to, for the job name `source`.
+pub const FILE_NAME: &str = "source.jlpos";
+
+/// Defines `\jl@put` for [`crate::boxes`], which places a marker before the box. Goes right after
+/// the preamble.
+pub const SETUP: &str = indoc! {r"
+    \makeatletter
+    \ifdefined\pdfsavepos\else
+      % LuaTeX drops the pdf prefix.
+      \let\pdfsavepos\savepos \let\pdflastxpos\lastxpos \let\pdflastypos\lastypos
+    \fi
+    % Positions are measured from the bottom of the page as the engine knows it, which is not
+    % necessarily the paper size in DVI mode.
+    \ifdefined\pdfpageheight \pdfpageheight=\paperheight
+    \else\ifdefined\pageheight \pageheight=\paperheight \fi\fi
+    \newwrite\jl@pos
+    \immediate\openout\jl@pos=\jobname.jlpos
+    % The physical page, counted before the writes on it are expanded.
+    \newcount\jl@page
+    \ifdefined\AddToHook
+      \AddToHook{shipout/before}{\global\advance\jl@page\@ne}
+    \else
+      \let\jl@shipout\shipout
+      \def\shipout{\global\advance\jl@page\@ne\jl@shipout}
+    \fi
+    \newcommand\jl@put[1]{%
+      % In vertical mode, this starts a paragraph without indentation.
+      \noindent\pdfsavepos
+      % The position and the page are only known at shipout, while the box is reused.
+      \edef\jl@write{\write\jl@pos{#1,\noexpand\the\jl@page,%
+        \noexpand\the\pdflastxpos,\noexpand\the\pdflastypos,\number\paperheight,%
+        \number\wd\jl@box,\number\ht\jl@box,\number\dp\jl@box}}%
+      \jl@write\usebox\jl@box}
+    \makeatother
+"};
+
+/// The position of a fragment on its page. All lengths are in pt, measured like SyncTeX does,
+/// i.e. from the top left corner of the page.
+#[derive(Clone, Debug)]
+pub struct Marker {
+    pub page: u32,
+    pub h: f64,
+    pub v: f64,
+    pub width: f64,
+    pub height: f64,
+    pub depth: f64,
+}
+
+/// Reads the markers written during compilation, keyed by the index of the fragment.
+pub fn read(path: &Path) -> Result<HashMap<usize, Marker>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let mut markers = HashMap::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let fields = line
+            .split(',')
+            .map(|field| field.trim().parse::<i64>())
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .filter(|fields| fields.len() == 8)
+            .with_context(|| format!("malformed marker: {}", line))?;
+        if fields[1] < 1 {
+            bail!("marker on page {}: {}", fields[1], line);
+        }
+        let pt = |sp: i64| sp as f64 / 65536.0;
+        markers.insert(
+            fields[0] as usize,
+            Marker {
+                page: fields[1] as u32,
+                h: pt(fields[2]),
+                v: pt(fields[4] - fields[3]),
+                width: pt(fields[5]),
+                height: pt(fields[6]),
+                depth: pt(fields[7]),
+            },
+        );
+    }
+    Ok(markers)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn read_str(content: &str) -> Result<HashMap<usize, Marker>> {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        read(file.path())
+    }
+
+    #[test]
+    fn read_markers() {
+        let markers = read_str(indoc! {"
+            0,1,4736286,50642297,55380582,655360,491520,131072
+
+            3,2,4736286,55380582,55380582,-65536,0,0
+        "})
+        .unwrap();
+        assert_eq!(markers.len(), 2);
+        let marker = &markers[&0];
+        assert_eq!(marker.page, 1);
+        assert_eq!(marker.h, 4736286.0 / 65536.0);
+        // From the bottom to the top of the page.
+        assert_eq!(marker.v, (55380582.0 - 50642297.0) / 65536.0);
+        assert_eq!(
+            (marker.width, marker.height, marker.depth),
+            (10.0, 7.5, 2.0)
+        );
+        let marker = &markers[&3];
+        assert_eq!((marker.page, marker.v, marker.width), (2, 0.0, -1.0));
+    }
+
+    #[test]
+    fn malformed_markers() {
+        for content in [
+            "0,1,2,3,4,5,6\n",
+            "0,1,2,3,4,5,6,x\n",
+            "0,0,2,3,4,5,6,7\n",
+            "0,-1,2,3,4,5,6,7\n",
+        ] {
+            assert!(read_str(content).is_err(), "{}", content);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Positioning of fragments through the `preview` package, which ships out every fragment as its
+//! own page, cropped to the box of the fragment enlarged by `\PreviewBbAdjust`.
+//!
+//! The page of a fragment is thus its region, and only the position of the baseline is left to
+//! find. It follows from the size of the box, which is written to the log for each fragment as
+//!
+//! ```text
+//! JustLaTeX: fragment <fragment> <height>+<depth>x<width>
+//! ```
+//!
+//! and from the margins around the box, which the package itself reports as
+//!
+//! ```text
+//! Preview: Tightpage <left> <bottom> <right> <top>
+//! ```
+//!
+//! All lengths are in sp.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use indoc::indoc;
+use regex::Regex;
+
+/// Loads the package. Goes right before `\begin{document}`.
+pub const PACKAGE: &str = r"\usepackage[active,tightpage]{preview}";
+
+/// Defines `\jl@put` for [`crate::boxes`], which puts the box into a preview. Goes right after the
+/// preamble.
+pub const SETUP: &str = indoc! {r"
+    \makeatletter
+    \newcommand\jl@put[1]{%
+      \typeout{JustLaTeX: fragment #1 \number\ht\jl@box+\number\dp\jl@box x\number\wd\jl@box}%
+      % In vertical mode, the preview would be a \vbox as wide as the text, with the indentation.
+      \noindent\begin{preview}\usebox\jl@box\end{preview}}
+    \makeatother
+"};
+
+/// Adds [`PACKAGE`] to the preamble, right before `\begin{document}`.
+pub fn add_package(preamble: &str) -> String {
+    match preamble.rfind(r"\begin{document}") {
+        Some(pos) => format!("{}{}\n{}", &preamble[..pos], PACKAGE, &preamble[pos..]),
+        None => format!("{}\n{}", preamble, PACKAGE),
+    }
+}
+
+/// The size of the box of a fragment, in pt.
+#[derive(Clone, Debug)]
+pub struct Snippet {
+    pub width: f64,
+    pub height: f64,
+    pub depth: f64,
+}
+
+/// The data written to the log during compilation.
+pub struct Previews {
+    /// How far the pages extend beyond the boxes of the fragments to the left, to the bottom, to the
+    /// right and to the top, in pt.
+    pub margins: [f64; 4],
+    /// The boxes of the fragments, keyed by the index of the fragment.
+    pub snippets: HashMap<usize, Snippet>,
+}
+
+/// Reads the data of the fragments from the log.
+pub fn read(log: &str) -> Result<Previews> {
+    let tightpage = Regex::new(r"Preview: Tightpage (-?\d+) (-?\d+) (-?\d+) (-?\d+)").unwrap();
+    let fragment = Regex::new(r"JustLaTeX: fragment (\d+) (-?\d+)\+(-?\d+)x(-?\d+)").unwrap();
+    let pt = |sp: &str| sp.parse::<i64>().map(|sp| sp as f64 / 65536.0);
+    let captures = tightpage
+        .captures(log)
+        .context("the preview package did not report the page margins (tightpage)")?;
+    // The package reports the corners of the page relative to those of the box.
+    let margins = [
+        -pt(&captures[1])?,
+        -pt(&captures[2])?,
+        pt(&captures[3])?,
+        pt(&captures[4])?,
+    ];
+    let mut snippets = HashMap::new();
+    for captures in fragment.captures_iter(log) {
+        snippets.insert(
+            captures[1].parse()?,
+            Snippet {
+                height: pt(&captures[2])?,
+                depth: pt(&captures[3])?,
+                width: pt(&captures[4])?,
+            },
+        );
+    }
+    Ok(Previews { margins, snippets })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_log() {
+        let log = indoc! {r"
+            Preview: Fontsize 10pt
+            Preview: Tightpage -65536 -131072 196608 262144
+            JustLaTeX: fragment 0 491520+131072x1310720
+            l.42 ...
+            JustLaTeX: fragment 2 0+0x-32768
+        "};
+        let previews = read(log).unwrap();
+        // The corners of the page lie outside the box, i.e. to the bottom left and the top right.
+        assert_eq!(previews.margins, [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(previews.snippets.len(), 2);
+        let snippet = &previews.snippets[&0];
+        assert_eq!(
+            (snippet.width, snippet.height, snippet.depth),
+            (20.0, 7.5, 2.0)
+        );
+        assert_eq!(previews.snippets[&2].width, -0.5);
+    }
+
+    #[test]
+    fn read_log_without_tightpage() {
+        let error = read("JustLaTeX: fragment 0 491520+131072x1310720\n")
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("tightpage"));
+    }
+}
@@ -316,7 +316,7 @@ pub fn optimize(tree: &Tree, eps: f64) -> Result<Vec<u8>> {
         }
     }
 
-    eprintln!(
+    log::info!(
         "SVG optimizer found {}/{} similar paths in {}s",
         count,
         total,
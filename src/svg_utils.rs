@@ -1,4 +1,11 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    io::Cursor,
+};
+
 use anyhow::{bail, Result};
+use quick_xml::events::{attributes::Attribute, BytesCData, BytesStart, Event};
 use regex::Regex;
 use usvg::{NodeExt, PathBbox};
 
@@ -34,8 +41,17 @@ pub fn paths_to_bboxes(tree: &usvg::Tree) -> Vec<PathBbox> {
 /// by usvg's parser by default (because it does not support @font-face), so we have to do some
 /// hacks here to help it.
 pub fn parse_to_tree(svg_data: &[u8]) -> Result<usvg::Tree> {
+    parse_to_tree_with_options(svg_data, false)
+}
+
+/// Like [`parse_to_tree`], but optionally keeps groups with IDs so that elements of the original SVG
+/// can be found in the tree.
+fn parse_to_tree_with_options(svg_data: &[u8], keep_named_groups: bool) -> Result<usvg::Tree> {
     let mut reader = quick_xml::Reader::from_bytes(svg_data);
-    let mut options = usvg::Options::default();
+    let mut options = usvg::Options {
+        keep_named_groups,
+        ..usvg::Options::default()
+    };
 
     let font_face_regex = Regex::new(
         // Follows the format of dvisvgm's FontWriter::writeCSSFontFace, defined in FontWriter.cpp.
@@ -210,3 +226,466 @@ pub fn refine_y_range(bboxes: &[PathBbox], y_min: f64, y_max: f64, tol: f64) ->
         (new_y_min, new_y_max)
     }
 }
+
+/// Elements whose contents are not drawn directly. Everything inside them is kept when cropping.
+const NON_RENDERING_ELEMENTS: &[&[u8]] = &[
+    b"defs",
+    b"style",
+    b"clipPath",
+    b"mask",
+    b"pattern",
+    b"marker",
+    b"symbol",
+    b"linearGradient",
+    b"radialGradient",
+    b"filter",
+    b"font",
+    b"metadata",
+    b"title",
+    b"desc",
+];
+
+/// Elements that draw something. These are the units of cropping.
+const GRAPHICS_ELEMENTS: &[&[u8]] = &[
+    b"path",
+    b"use",
+    b"text",
+    b"rect",
+    b"circle",
+    b"ellipse",
+    b"line",
+    b"polyline",
+    b"polygon",
+    b"image",
+];
+
+/// Prefix of the IDs we assign to graphics elements that do not have one.
+const CROP_ID_PREFIX: &str = "jl-crop-";
+
+/// Crops regions out of an SVG (a page returned by dvisvgm) into standalone SVGs.
+///
+/// Cropping works on the level of graphics elements: an element is kept if its bounding box
+/// intersects the region. To find out the bounding box of each element, every graphics element is
+/// given an ID (if it does not have one yet) before the SVG is parsed by usvg, which preserves IDs
+/// when asked to keep named groups.
+pub struct Cropper {
+    /// The SVG with IDs assigned to all graphics elements.
+    data: Vec<u8>,
+    /// Bounding boxes of graphics elements by ID.
+    bboxes: HashMap<String, PathBbox>,
+    /// IDs referenced from inside each element with an ID in non-rendering elements, e.g. the glyphs
+    /// a clip path uses, including the IDs of its children.
+    def_refs: HashMap<String, Vec<String>>,
+    /// Font families of the classes dvisvgm defines for text in DVI mode.
+    font_families: HashMap<String, String>,
+}
+
+impl Cropper {
+    pub fn new(svg_data: &[u8]) -> Result<Self> {
+        let text_class = text_class_regex();
+        let mut reader = quick_xml::Reader::from_bytes(svg_data);
+        let mut writer = quick_xml::Writer::new(Cursor::new(vec![]));
+        let mut non_rendering_depth = 0;
+        let mut n_assigned = 0;
+        // For each open element inside non-rendering elements, its ID if it has one.
+        let mut def_ids: Vec<Option<String>> = vec![];
+        let mut def_refs: HashMap<String, Vec<String>> = HashMap::new();
+        let mut font_families = HashMap::new();
+        loop {
+            let event = reader.read_event_unbuffered()?;
+            if non_rendering_depth > 0 {
+                if let Event::Start(e) | Event::Empty(e) = &event {
+                    let own_id = e
+                        .try_get_attribute("id")?
+                        .map(|id| String::from_utf8_lossy(&id.value).into_owned());
+                    let parent_id = def_ids.iter().rev().flatten().next().cloned();
+                    // Whatever uses an element uses its children as well.
+                    if let (Some(own_id), Some(parent_id)) = (&own_id, &parent_id) {
+                        def_refs
+                            .entry(parent_id.clone())
+                            .or_default()
+                            .push(own_id.clone());
+                    }
+                    let id = own_id.or(parent_id);
+                    if let Some(id) = &id {
+                        def_refs
+                            .entry(id.clone())
+                            .or_default()
+                            .extend(references(e)?);
+                    }
+                    if let Event::Start(_) = event {
+                        def_ids.push(id);
+                    }
+                }
+                if let Event::End(_) = event {
+                    def_ids.pop();
+                }
+                let content = match &event {
+                    Event::Text(e) => Some(String::from_utf8_lossy(e)),
+                    Event::CData(e) => Some(String::from_utf8_lossy(e)),
+                    _ => None,
+                };
+                if let Some(content) = content {
+                    for capture in text_class.captures_iter(&content) {
+                        font_families.insert(capture[1].to_string(), capture[2].trim().to_string());
+                    }
+                }
+            }
+            match event {
+                Event::Start(e) if NON_RENDERING_ELEMENTS.contains(&e.local_name()) => {
+                    non_rendering_depth += 1;
+                    writer.write_event(Event::Start(e))?;
+                }
+                Event::End(e) if NON_RENDERING_ELEMENTS.contains(&e.local_name()) => {
+                    non_rendering_depth -= 1;
+                    writer.write_event(Event::End(e))?;
+                }
+                Event::Start(mut e)
+                    if non_rendering_depth == 0
+                        && GRAPHICS_ELEMENTS.contains(&e.local_name())
+                        && e.try_get_attribute("id")?.is_none() =>
+                {
+                    e.push_attribute(("id", format!("{}{}", CROP_ID_PREFIX, n_assigned).as_str()));
+                    n_assigned += 1;
+                    writer.write_event(Event::Start(e))?;
+                }
+                Event::Empty(mut e)
+                    if non_rendering_depth == 0
+                        && GRAPHICS_ELEMENTS.contains(&e.local_name())
+                        && e.try_get_attribute("id")?.is_none() =>
+                {
+                    e.push_attribute(("id", format!("{}{}", CROP_ID_PREFIX, n_assigned).as_str()));
+                    n_assigned += 1;
+                    writer.write_event(Event::Empty(e))?;
+                }
+                Event::Eof => break,
+                e => writer.write_event(e)?,
+            }
+        }
+        let data = writer.into_inner().into_inner();
+
+        let tree = parse_to_tree_with_options(&data, true)?;
+        let mut bboxes = HashMap::new();
+        for node in tree.root().descendants() {
+            let id = node.id().to_string();
+            if id.is_empty() {
+                continue;
+            }
+            if let Some(bbox) = node.calculate_bbox() {
+                bboxes.insert(id, bbox);
+            }
+        }
+        Ok(Self {
+            data,
+            bboxes,
+            def_refs,
+            font_families,
+        })
+    }
+
+    /// Crops the region `(x, y, width, height)` (in SVG coordinates) into a standalone SVG.
+    ///
+    /// Only the definitions (glyphs, clip paths, fonts, etc.) used by what is left of the page are
+    /// kept. All IDs and references to them are prefixed by `id_prefix`, which allows multiple
+    /// cropped SVGs to be inlined into the same HTML as long as each gets its own prefix. Extra
+    /// attributes can be added to the root element.
+    pub fn crop(
+        &self,
+        (x, y, width, height): (f64, f64, f64, f64),
+        id_prefix: &str,
+        root_attributes: &[(&str, &str)],
+    ) -> Result<Vec<u8>> {
+        let is_outside = |e: &BytesStart| -> Result<bool> {
+            let id = match e.try_get_attribute("id")? {
+                Some(id) => String::from_utf8_lossy(&id.value).into_owned(),
+                None => return Ok(false),
+            };
+            // Elements usvg does not know about are kept.
+            Ok(self.bboxes.get(&id).is_some_and(|bbox| {
+                bbox.right() < x
+                    || bbox.left() > x + width
+                    || bbox.bottom() < y
+                    || bbox.top() > y + height
+            }))
+        };
+        let (used_ids, used_fonts) = self.used_definitions(&is_outside)?;
+        let is_unused = |e: &BytesStart| -> Result<bool> {
+            Ok(match e.try_get_attribute("id")? {
+                Some(id) => !used_ids.contains(String::from_utf8_lossy(&id.value).as_ref()),
+                None => false,
+            })
+        };
+        let text_class = text_class_regex();
+        let font_face = Regex::new(r"^@font-face\s*\{\s*font-family:([^;}]+)").unwrap();
+        // Keeps the CSS rules dvisvgm writes for text, one per line, if their fonts are used.
+        let prune_css = |css: &str| -> String {
+            css.lines()
+                .filter(|line| {
+                    let line = line.trim();
+                    match (text_class.captures(line), font_face.captures(line)) {
+                        (Some(capture), _) => used_fonts.contains(capture[2].trim()),
+                        (_, Some(capture)) => used_fonts.contains(capture[1].trim()),
+                        _ => true,
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+
+        let mut reader = quick_xml::Reader::from_bytes(&self.data);
+        let mut writer = quick_xml::Writer::new(Cursor::new(vec![]));
+        let mut non_rendering_depth = 0;
+        // Depth inside an element that is being left out.
+        let mut skip_depth = 0;
+        loop {
+            let event = reader.read_event_unbuffered()?;
+            if skip_depth > 0 {
+                match event {
+                    Event::Start(_) => skip_depth += 1,
+                    Event::End(_) => skip_depth -= 1,
+                    Event::Eof => break,
+                    _ => {}
+                }
+                continue;
+            }
+            match event {
+                Event::Start(e) if e.local_name() == b"svg" => {
+                    let mut root = BytesStart::owned_name("svg");
+                    for attr in e.attributes() {
+                        let attr = attr?;
+                        if !matches!(attr.key, b"width" | b"height" | b"viewBox") {
+                            root.push_attribute(attr);
+                        }
+                    }
+                    root.push_attribute(("width", format!("{:.2}pt", width).as_str()));
+                    root.push_attribute(("height", format!("{:.2}pt", height).as_str()));
+                    root.push_attribute((
+                        "viewBox",
+                        format!("{:.2} {:.2} {:.2} {:.2}", x, y, width, height).as_str(),
+                    ));
+                    for attr in root_attributes {
+                        root.push_attribute(*attr);
+                    }
+                    writer.write_event(Event::Start(root))?;
+                }
+                Event::Start(e) if non_rendering_depth > 0 && is_unused(&e)? => {
+                    skip_depth = 1;
+                }
+                Event::Empty(e) if non_rendering_depth > 0 && is_unused(&e)? => {}
+                Event::Start(e) if NON_RENDERING_ELEMENTS.contains(&e.local_name()) => {
+                    non_rendering_depth += 1;
+                    writer.write_event(Event::Start(prefix_ids(&e, id_prefix)?))?;
+                }
+                Event::End(e) if NON_RENDERING_ELEMENTS.contains(&e.local_name()) => {
+                    non_rendering_depth -= 1;
+                    writer.write_event(Event::End(e))?;
+                }
+                Event::CData(e) if non_rendering_depth > 0 => {
+                    let css = prune_css(&String::from_utf8_lossy(&e));
+                    writer.write_event(Event::CData(BytesCData::from_str(&css)))?;
+                }
+                Event::Start(e)
+                    if non_rendering_depth == 0
+                        && GRAPHICS_ELEMENTS.contains(&e.local_name())
+                        && is_outside(&e)? =>
+                {
+                    skip_depth = 1;
+                }
+                Event::Empty(e)
+                    if non_rendering_depth == 0
+                        && GRAPHICS_ELEMENTS.contains(&e.local_name())
+                        && is_outside(&e)? => {}
+                Event::Start(e) => writer.write_event(Event::Start(prefix_ids(&e, id_prefix)?))?,
+                Event::Empty(e) => writer.write_event(Event::Empty(prefix_ids(&e, id_prefix)?))?,
+                Event::Comment(_) => {}
+                Event::Eof => break,
+                e => writer.write_event(e)?,
+            }
+        }
+        Ok(writer.into_inner().into_inner())
+    }
+
+    /// Finds the IDs of definitions and the font families used by the graphics elements that are
+    /// not left out when cropping, including definitions used by other used definitions.
+    fn used_definitions(
+        &self,
+        is_outside: &dyn Fn(&BytesStart) -> Result<bool>,
+    ) -> Result<(HashSet<String>, HashSet<String>)> {
+        let mut reader = quick_xml::Reader::from_bytes(&self.data);
+        let mut non_rendering_depth = 0;
+        let mut skip_depth = 0;
+        let mut pending = vec![];
+        let mut used_fonts = HashSet::new();
+        loop {
+            let event = reader.read_event_unbuffered()?;
+            if skip_depth > 0 {
+                match event {
+                    Event::Start(_) => skip_depth += 1,
+                    Event::End(_) => skip_depth -= 1,
+                    Event::Eof => break,
+                    _ => {}
+                }
+                continue;
+            }
+            match &event {
+                Event::Start(e) if NON_RENDERING_ELEMENTS.contains(&e.local_name()) => {
+                    non_rendering_depth += 1;
+                }
+                Event::End(e) if NON_RENDERING_ELEMENTS.contains(&e.local_name()) => {
+                    non_rendering_depth -= 1;
+                }
+                Event::Start(e) | Event::Empty(e) if non_rendering_depth == 0 => {
+                    if GRAPHICS_ELEMENTS.contains(&e.local_name()) && is_outside(e)? {
+                        if let Event::Start(_) = event {
+                            skip_depth = 1;
+                        }
+                        continue;
+                    }
+                    pending.extend(references(e)?);
+                    if let Some(class) = e.try_get_attribute("class")? {
+                        for class in String::from_utf8_lossy(&class.value).split_whitespace() {
+                            if let Some(family) = self.font_families.get(class) {
+                                used_fonts.insert(family.clone());
+                            }
+                        }
+                    }
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        let mut used_ids = HashSet::new();
+        while let Some(id) = pending.pop() {
+            if let Some(refs) = self.def_refs.get(&id) {
+                if !used_ids.contains(&id) {
+                    pending.extend(refs.iter().cloned());
+                }
+            }
+            used_ids.insert(id);
+        }
+        Ok((used_ids, used_fonts))
+    }
+}
+
+/// Matches the CSS rules dvisvgm writes for the classes of text elements in DVI mode, e.g.
+/// `text.f0 {font-family:cmr12;font-size:11.955168px}`.
+fn text_class_regex() -> Regex {
+    Regex::new(r"(?m)^\s*text\.([-\w]+)\s*\{\s*font-family:([^;}]+)").unwrap()
+}
+
+/// Returns the IDs an element refers to in its attributes, through `href="#id"` or `url(#id)`.
+fn references(e: &BytesStart) -> Result<Vec<String>> {
+    let url = Regex::new(r"url\(#([^)]+)\)").unwrap();
+    let mut refs = vec![];
+    for attr in e.attributes() {
+        let attr = attr?;
+        let value = String::from_utf8_lossy(&attr.value);
+        match attr.key {
+            b"href" | b"xlink:href" if value.starts_with('#') => refs.push(value[1..].to_string()),
+            _ => refs.extend(
+                url.captures_iter(&value)
+                    .map(|capture| capture[1].to_string()),
+            ),
+        }
+    }
+    Ok(refs)
+}
+
+/// Prefixes the ID of an element and all references to other IDs in its attributes. IDs we have
+/// assigned ourselves are dropped.
+fn prefix_ids<'a>(e: &BytesStart<'a>, prefix: &str) -> Result<BytesStart<'static>> {
+    let mut new_e = BytesStart::owned_name(e.name());
+    for attr in e.attributes() {
+        let attr = attr?;
+        let value = String::from_utf8_lossy(&attr.value);
+        let new_value = match attr.key {
+            b"id" if value.starts_with(CROP_ID_PREFIX) => continue,
+            b"id" => format!("{}{}", prefix, value),
+            b"href" | b"xlink:href" if value.starts_with('#') => {
+                format!("#{}{}", prefix, &value[1..])
+            }
+            _ if value.contains("url(#") => value.replace("url(#", &format!("url(#{}", prefix)),
+            _ => {
+                new_e.push_attribute(attr);
+                continue;
+            }
+        };
+        new_e.push_attribute(Attribute {
+            key: attr.key,
+            value: Cow::Owned(new_value.into_bytes()),
+        });
+    }
+    Ok(new_e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crop(svg: &str, region: (f64, f64, f64, f64)) -> String {
+        let cropper = Cropper::new(svg.as_bytes()).unwrap();
+        String::from_utf8(cropper.crop(region, "p-", &[]).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn crop_keeps_used_definitions() {
+        // Like the output of dvisvgm in PDF mode.
+        let svg = indoc::indoc! {r#"
+            <?xml version='1.0' encoding='UTF-8'?>
+            <svg version='1.1' xmlns='http://www.w3.org/2000/svg' xmlns:xlink='http://www.w3.org/1999/xlink' width='100pt' height='100pt' viewBox='0 0 100 100'>
+            <defs>
+            <path id='g0-1' d='M0 0H5V-5H0Z'/>
+            <path id='g0-2' d='M0 0H5V-8H0Z'/>
+            <path id='g0-3' d='M0 0H5V-3H0Z'/>
+            <clipPath id='c0'><path id='c0-path' d='M0 50H100V100H0Z'/></clipPath>
+            </defs>
+            <g id='page1'>
+            <use x='10' y='20' xlink:href='#g0-1'/>
+            <use x='10' y='80' xlink:href='#g0-2' clip-path='url(#c0)'/>
+            <use x='10' y='90' xlink:href='#g0-3'/>
+            </g>
+            </svg>
+        "#};
+        let top = crop(svg, (0.0, 0.0, 100.0, 30.0));
+        assert!(top.contains("id=\"p-g0-1\""));
+        assert!(!top.contains("g0-2") && !top.contains("g0-3") && !top.contains("c0"));
+
+        let bottom = crop(svg, (0.0, 70.0, 100.0, 30.0));
+        assert!(!bottom.contains("g0-1"));
+        assert!(bottom.contains("id=\"p-g0-2\"") && bottom.contains("id=\"p-g0-3\""));
+        // The clip path is kept along with its children.
+        assert!(bottom.contains("id=\"p-c0\"") && bottom.contains("id=\"p-c0-path\""));
+        assert!(bottom.contains("url(#p-c0)"));
+    }
+
+    #[test]
+    fn crop_keeps_used_fonts() {
+        // Like the output of dvisvgm in DVI mode. usvg cannot lay out the text without the actual
+        // fonts, so the classes are put on rectangles instead.
+        let svg = indoc::indoc! {r#"
+            <?xml version='1.0' encoding='UTF-8'?>
+            <svg version='1.1' xmlns='http://www.w3.org/2000/svg' width='100pt' height='100pt' viewBox='0 0 100 100'>
+            <style type='text/css'>
+            <![CDATA[@font-face{font-family:cmr12;src:url(data:application/x-font-ttf;base64,AAAA);}
+            @font-face{font-family:cmmi12;src:url(data:application/x-font-ttf;base64,BBBB);}
+            text.f0 {font-family:cmr12;font-size:11.955168px}
+            text.f1 {font-family:cmmi12;font-size:11.955168px}
+            text.f2 {font-family:cmr12;font-size:8.966376px}
+            ]]>
+            </style>
+            <g id='page1'>
+            <rect class='f0' x='10' y='10' width='10' height='10'/>
+            <rect class='f1' x='10' y='80' width='10' height='10'/>
+            </g>
+            </svg>
+        "#};
+        let top = crop(svg, (0.0, 0.0, 100.0, 30.0));
+        assert!(top.contains("font-family:cmr12;src:") && top.contains("text.f0"));
+        assert!(top.contains("text.f2"));
+        assert!(!top.contains("cmmi12") && !top.contains("text.f1"));
+
+        let bottom = crop(svg, (0.0, 70.0, 100.0, 30.0));
+        assert!(bottom.contains("font-family:cmmi12;src:") && bottom.contains("text.f1"));
+        assert!(!bottom.contains("cmr12"));
+    }
+}
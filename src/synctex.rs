@@ -1,66 +1,260 @@
-#![allow(non_upper_case_globals)]
-#![allow(non_camel_case_types)]
-#![allow(non_snake_case)]
-#![allow(dead_code)]
+//! A reader for the SyncTeX files written by TeX engines under `-synctex`.
+//!
+//! Only what display queries (from a line of the source to boxes in the output) need is read. A
+//! SyncTeX file lists, page by page, the boxes TeX shipped out along with the nodes inside them,
+//! each tagged with the input file and line it originates from. See the `synctex` man page for
+//! the format.
 
-use std::{ffi::CString, os::raw::c_int, path::Path, hash::Hash};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{ErrorKind, Read},
+    ops::Range,
+    path::{Path, PathBuf},
+};
 
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
 use ordered_float::OrderedFloat;
 
-include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+/// The tag of the main input file, i.e. the one given to the engine.
+const MAIN_TAG: u32 = 1;
 
-pub struct Scanner(synctex_scanner_p);
+#[derive(Default)]
+pub struct Scanner {
+    /// The paths of the input files, keyed by tag.
+    inputs: HashMap<u32, String>,
+    /// The horizontal boxes, in the order of the file.
+    boxes: Vec<TeXBox>,
+    /// For each line of the main input file, the boxes containing its output as indices into
+    /// [`Self::boxes`]. Output from other input files, i.e. files pulled in by `\input`, is
+    /// attributed to the line of the previous node from the main input file, usually the box it is
+    /// in. Macros defined in packages need no such care, as their output is tagged with the line
+    /// TeX was reading.
+    lines: BTreeMap<usize, Vec<usize>>,
+}
 
 impl Scanner {
-    pub fn new(output: impl AsRef<Path>, build_dir: impl AsRef<Path>) -> Self {
-        let output = CString::new(output.as_ref().to_string_lossy().as_bytes()).unwrap();
-        let build_dir = CString::new(build_dir.as_ref().to_string_lossy().as_bytes()).unwrap();
-        Self(unsafe {
-            synctex_scanner_new_with_output_file(output.as_ptr(), build_dir.as_ptr(), 1)
-        })
+    /// Reads the SyncTeX file accompanying `output`, which is looked for next to `output` and in
+    /// `build_dir`. Fails if there is none or if it is corrupt.
+    pub fn new(output: impl AsRef<Path>, build_dir: impl AsRef<Path>) -> Result<Self> {
+        let (path, content) = read_synctex_file(output.as_ref(), build_dir.as_ref())?;
+        Self::parse(&content).with_context(|| format!("parsing {}", path.display()))
     }
 
-    pub fn query(&self, line: usize) -> Vec<TeXBox> {
-        fn texpt_to_f64(x: i32) -> OrderedFloat<f64> {
-            (x as f64 / 65536.0).into()
+    fn parse(content: &str) -> Result<Self> {
+        if !content.starts_with("SyncTeX Version:") {
+            bail!("not a SyncTeX file");
         }
+        let mut scanner = Self::default();
+        let mut unit = 1.0;
+        let mut in_content = false;
+        let mut page = 0;
+        // Forms are not attached to any page, so their nodes are left out.
+        let mut form_depth = 0;
+        // The boxes enclosing the current node, as indices into `scanner.boxes` for hboxes.
+        let mut parents: Vec<Option<usize>> = vec![];
+        // The line of the main input file the current node is attributed to.
+        let mut main_line = None;
+        let mut last = Record::default();
+        let mut complete = false;
+        for (i, line) in content.lines().enumerate() {
+            let malformed = || format!("malformed line {}: {}", i + 1, line);
+            if let Some(input) = line.strip_prefix("Input:") {
+                let (tag, path) = input.split_once(':').with_context(malformed)?;
+                let tag = tag.parse().ok().with_context(malformed)?;
+                scanner.inputs.insert(tag, path.to_string());
+                continue;
+            }
+            if !in_content {
+                if let Some(value) = line.strip_prefix("Unit:") {
+                    unit = value.trim().parse().ok().with_context(malformed)?;
+                } else if line.starts_with("Content:") {
+                    in_content = true;
+                }
+                continue;
+            }
+            if line.starts_with("Postamble:") {
+                complete = true;
+                break;
+            }
+            let mut chars = line.chars();
+            let (kind, rest) = match chars.next() {
+                Some(kind) => (kind, chars.as_str()),
+                None => continue,
+            };
+            match kind {
+                '{' => {
+                    page = rest.parse().ok().with_context(malformed)?;
+                    parents.clear();
+                    main_line = None;
+                }
+                '}' => parents.clear(),
+                '<' => form_depth += 1,
+                '>' => form_depth -= 1,
+                ']' if form_depth == 0 => {
+                    parents.pop();
+                    // A vbox ends the body of a page, after which TeX produces headers and
+                    // footers which belong to no line.
+                    main_line = None;
+                }
+                ')' if form_depth == 0 => {
+                    parents.pop();
+                }
+                '[' | '(' | 'v' | 'h' | 'k' | 'g' | 'r' | '$' | 'x' if form_depth == 0 => {
+                    let record = Record::parse(rest, &last).with_context(malformed)?;
+                    last = record;
+                    if record.tag == MAIN_TAG {
+                        main_line = Some(record.line);
+                    }
+                    // Only hboxes are of interest: vboxes are usually whole pages or paragraphs.
+                    // Other nodes stand for the box they are in, as in the SyncTeX library.
+                    let index = match kind {
+                        '(' | 'v' | 'h' => {
+                            let scale = |x: i64| OrderedFloat(x as f64 * unit / 65536.0);
+                            scanner.boxes.push(TeXBox {
+                                h: scale(record.h),
+                                v: scale(record.v),
+                                height: scale(record.height),
+                                width: scale(record.width),
+                                depth: scale(record.depth),
+                                page,
+                            });
+                            Some(scanner.boxes.len() - 1)
+                        }
+                        '[' => None,
+                        _ => parents.last().copied().flatten(),
+                    };
+                    if matches!(kind, '[' | '(') {
+                        parents.push(index);
+                    }
+                    if let (Some(index), Some(line)) = (index, main_line) {
+                        let indices = scanner.lines.entry(line).or_default();
+                        if !indices.contains(&index) {
+                            indices.push(index);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !complete {
+            // TeX writes the postamble last, so the file is incomplete, e.g. because TeX crashed.
+            bail!("truncated SyncTeX file");
+        }
+        Ok(scanner)
+    }
 
-        unsafe {
-            let name = synctex_scanner_get_name(self.0, 1);
-            let result = synctex_display_query(self.0, name, line as c_int, 0, -1);
-            let mut ret = vec![];
-            if result > 0 {
-                let mut node = synctex_scanner_next_result(self.0);
-                while !node.is_null() {
-                    ret.push(TeXBox {
-                        h: texpt_to_f64(synctex_node_box_h(node)),
-                        v: texpt_to_f64(synctex_node_box_v(node)),
-                        height: texpt_to_f64(synctex_node_box_height(node)),
-                        width: texpt_to_f64(synctex_node_box_width(node)),
-                        depth: texpt_to_f64(synctex_node_box_depth(node)),
-                        page: synctex_node_page(node) as u32,
-                        // ty: String::from(CStr::from_ptr(synctex_node_isa(node)).to_str().unwrap())
-                    });
-                    node = synctex_scanner_next_result(self.0);
+    /// Returns the hboxes containing the output of some lines of the main input file, including
+    /// output from other input files produced while TeX was reading these lines. Like SyncTeX
+    /// viewers, if the lines have no output at all, the closest line with some is used instead.
+    pub fn query(&self, lines: Range<usize>) -> Result<Vec<TeXBox>> {
+        const MAX_TRIES: usize = 100;
+        if !self.inputs.contains_key(&MAIN_TAG) {
+            bail!("the SyncTeX file lists no input file");
+        }
+        let mut indices = self
+            .lines
+            .range(lines.clone())
+            .flat_map(|(_, indices)| indices)
+            .collect::<Vec<_>>();
+        if indices.is_empty() {
+            let max_line = self.lines.keys().next_back().copied().unwrap_or_default();
+            // Try the lines line, line + 1, line - 1, line + 2, line - 2 and so on.
+            let (mut line, mut offset) = (lines.start.min(max_line) as i64, 1);
+            for _ in 0..MAX_TRIES {
+                if let Some(found) = usize::try_from(line).ok().and_then(|l| self.lines.get(&l)) {
+                    indices.extend(found);
+                    break;
                 }
+                line += offset;
+                offset = if offset > 0 { -offset - 1 } else { -offset + 1 };
             }
-            ret
         }
+        indices.sort_unstable();
+        indices.dedup();
+        Ok(indices
+            .into_iter()
+            .map(|&i| self.boxes[i].clone())
+            .collect())
     }
+}
 
-    pub fn dump(&self) {
-        unsafe {
-            synctex_scanner_display(self.0);
+/// The fields of a node record, i.e. `tag,line:h,v:width,height,depth` where the dimensions after
+/// the second colon are only present for some nodes, and `=` stands for the previous value.
+#[derive(Clone, Copy, Default)]
+struct Record {
+    tag: u32,
+    line: usize,
+    h: i64,
+    v: i64,
+    width: i64,
+    height: i64,
+    depth: i64,
+}
+
+impl Record {
+    fn parse(s: &str, last: &Self) -> Option<Self> {
+        let mut parts = s.split(':');
+        let mut link = parts.next()?.split(',');
+        let mut point = parts.next()?.split(',');
+        let mut size = parts.next().unwrap_or_default().split(',');
+        fn field<T: std::str::FromStr + Copy>(value: Option<&str>, last: T) -> Option<T> {
+            match value.map(str::trim) {
+                Some("=") => Some(last),
+                Some(value) => value.parse().ok(),
+                None => None,
+            }
         }
+        Some(Self {
+            tag: field(link.next(), last.tag)?,
+            // A column may follow the line, which we do not need.
+            line: field(link.next(), last.line)?,
+            h: field(point.next(), last.h)?,
+            v: field(point.next(), last.v)?,
+            width: field(size.next(), 0).unwrap_or_default(),
+            height: field(size.next(), 0).unwrap_or_default(),
+            depth: field(size.next(), 0).unwrap_or_default(),
+        })
     }
 }
 
-impl Drop for Scanner {
-    fn drop(&mut self) {
-        unsafe {
-            synctex_scanner_free(self.0);
+/// Reads `<output without extension>.synctex(.gz)`, next to `output` or in `build_dir`.
+fn read_synctex_file(output: &Path, build_dir: &Path) -> Result<(PathBuf, String)> {
+    let stem = output.file_stem().context("invalid output path")?;
+    let dirs = [
+        output.parent().map(Path::to_path_buf),
+        Some(build_dir.into()),
+    ];
+    let candidates = dirs.iter().flatten().flat_map(|dir| {
+        ["synctex.gz", "synctex"].map(|ext| {
+            let mut name = stem.to_os_string();
+            name.push(".");
+            name.push(ext);
+            dir.join(name)
+        })
+    });
+    for path in candidates {
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("opening {}", path.display())),
+        };
+        let mut bytes = vec![];
+        if path.extension().is_some_and(|ext| ext == "gz") {
+            GzDecoder::new(file).read_to_end(&mut bytes)
+        } else {
+            file.read_to_end(&mut bytes)
         }
+        .with_context(|| format!("reading {}", path.display()))?;
+        // File names are not necessarily UTF-8, but the records we need are ASCII.
+        let content = String::from_utf8_lossy(&bytes).into_owned();
+        return Ok((path, content));
     }
+    bail!(
+        "no SyncTeX file found for {} (does the engine support -synctex?)",
+        output.display()
+    )
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
@@ -71,5 +265,124 @@ pub struct TeXBox {
     pub width: OrderedFloat<f64>,
     pub depth: OrderedFloat<f64>,
     pub page: u32,
-    // pub ty: String,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, slice};
+
+    use super::*;
+
+    fn fixtures() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+    }
+
+    fn pt(sp: i64) -> OrderedFloat<f64> {
+        OrderedFloat(sp as f64 / 65536.0)
+    }
+
+    // The fixtures come with their sources, see tests/fixtures/make.sh.
+
+    #[test]
+    fn pdflatex() {
+        // Compressed, next to the output.
+        let scanner = Scanner::new(fixtures().join("pdflatex.pdf"), "/nonexistent").unwrap();
+        // Lines of paragraphs are as wide as the text.
+        let inline = TeXBox {
+            h: pt(4736286),
+            v: pt(5478016),
+            height: pt(531211),
+            width: pt(22609920),
+            depth: pt(0),
+            page: 1,
+        };
+        assert_eq!(scanner.query(6..7).unwrap(), slice::from_ref(&inline));
+        let display = TeXBox {
+            h: pt(14402846),
+            v: pt(7060480),
+            height: pt(1245184),
+            width: pt(3276800),
+            depth: pt(393216),
+            page: 1,
+        };
+        assert_eq!(scanner.query(8..9).unwrap(), slice::from_ref(&display));
+        assert_eq!(scanner.query(6..9).unwrap(), [inline, display.clone()]);
+        // Line 10 only has output in a form, so the closest line with output is used.
+        assert_eq!(scanner.query(10..11).unwrap(), [display]);
+        assert_eq!(scanner.query(0..100).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn input() {
+        let scanner = Scanner::new(fixtures().join("pdflatex.pdf"), "/nonexistent").unwrap();
+        assert!(scanner
+            .inputs
+            .values()
+            .any(|path| path.ends_with("/input.tex")));
+        // The output of input.tex is attributed to the line of the main input file of the box it
+        // is in, i.e. the paragraph ended by the blank line after the \input, rather than to the
+        // lines of input.tex.
+        assert_eq!(
+            scanner.lines.keys().copied().collect::<Vec<_>>(),
+            [6, 7, 8, 14]
+        );
+        let boxes = scanner.query(13..14).unwrap();
+        assert_eq!(boxes.len(), 1);
+        assert_eq!(
+            (boxes[0].h, boxes[0].v, boxes[0].height, boxes[0].page),
+            (pt(4736286), pt(5478016), pt(431117), 2)
+        );
+    }
+
+    #[test]
+    fn xelatex() {
+        // Uncompressed, in the build directory.
+        let scanner = Scanner::new("/nonexistent/xelatex.xdv", fixtures()).unwrap();
+        // The records of boxes carry a column after the line.
+        let boxes = scanner.query(5..6).unwrap();
+        assert_eq!(boxes.len(), 1);
+        assert_eq!((boxes[0].h, boxes[0].width), (pt(4736286), pt(22609920)));
+        let boxes = scanner.query(7..8).unwrap();
+        assert_eq!(boxes.len(), 1);
+        assert_eq!((boxes[0].v, boxes[0].depth), (pt(6619136), pt(131072)));
+    }
+
+    #[test]
+    fn unit() {
+        let content = fs::read_to_string(fixtures().join("xelatex.synctex")).unwrap();
+        let scanner = Scanner::parse(&content.replace("Unit:1", "Unit:2")).unwrap();
+        let boxes = scanner.query(7..8).unwrap();
+        assert_eq!((boxes[0].h, boxes[0].v), (pt(2 * 4736286), pt(2 * 6619136)));
+    }
+
+    #[test]
+    fn carry_over() {
+        let content = indoc::indoc! {"
+            SyncTeX Version:1
+            Input:1:source.tex
+            Content:
+            {1
+            (1,3:100,200:300,400,500
+            )
+            (=,=:=,=:600,700,800
+            )
+            }1
+            Postamble:
+        "};
+        let boxes = Scanner::parse(content).unwrap().query(3..4).unwrap();
+        assert_eq!(boxes.len(), 2);
+        assert_eq!((boxes[1].h, boxes[1].v), (pt(100), pt(200)));
+        assert_eq!(boxes[1].width, pt(600));
+    }
+
+    #[test]
+    fn invalid_files() {
+        let content = fs::read_to_string(fixtures().join("xelatex.synctex")).unwrap();
+        let truncated = &content[..content.find("Postamble:").unwrap()];
+        let error = Scanner::parse(truncated).err().unwrap();
+        assert!(error.to_string().contains("truncated"));
+        assert!(Scanner::parse("").is_err());
+        assert!(Scanner::parse(&content.replace("x1,7:4736286,=", "x1,7:4736286")).is_err());
+        assert!(Scanner::new(fixtures().join("missing.pdf"), fixtures()).is_err());
+    }
+}
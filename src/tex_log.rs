@@ -0,0 +1,391 @@
+//! Parsing of TeX log files.
+//!
+//! TeX logs are meant for humans and not really machine-readable, so everything here is a best
+//! effort. Under `-interaction=nonstopmode`, an error looks like
+//!
+//! ```text
+//! ! Undefined control sequence.
+//! <recently read> \foo
+//!
+//! l.12 $\foo
+//!           {x}$
+//! ```
+//!
+//! where the `l.<n>` marker tells the line in the source file TeX was reading when the error
+//! occurred, and the two lines of the marker show how far on that line TeX has got.
+//!
+//! Warnings come in a variety of formats. Most of them mention the offending line somewhere, e.g.
+//!
+//! ```text
+//! Overfull \hbox (15.0pt too wide) in paragraph at lines 12--12
+//! LaTeX Warning: Reference `foo' on page 1 undefined on input line 14.
+//! ```
+//!
+//! but some (notably missing characters) do not.
+
+use regex::Regex;
+
+/// Maximum number of log lines between an error message and its `l.<n>` marker.
+const MAX_ERROR_CONTEXT: usize = 16;
+/// Maximum number of log lines a single warning is allowed to span.
+const MAX_WARNING_LINES: usize = 8;
+/// TeX hard-wraps lines in the log at this length (the `max_print_line` setting of TeX Live).
+const MAX_PRINT_LINE: usize = 79;
+
+#[derive(Clone, Debug)]
+pub struct TeXError {
+    /// The error message, without the leading "! ".
+    pub message: String,
+    /// Line in the source file that TeX reported the error on, if any.
+    pub line: Option<usize>,
+    /// The `l.<n>` marker and the line after it.
+    pub context: Vec<String>,
+}
+
+/// Extracts all errors from a TeX log.
+pub fn parse_errors(log: &str) -> Vec<TeXError> {
+    let line_marker = Regex::new(r"^l\.(\d+) ").unwrap();
+    // Continuation lines of package errors are prefixed with "(package name)".
+    let continuation = Regex::new(r"^\([^()\s]+\)\s+").unwrap();
+    let lines = log.lines().collect::<Vec<_>>();
+    let mut errors = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        let message = match line.strip_prefix("! ") {
+            Some(message) => message,
+            None => continue,
+        };
+        let mut message = message.trim_end().to_string();
+        let (mut end, mut prev) = (i + 1, *line);
+        while let Some(next) = lines.get(end) {
+            if prev.chars().count() >= MAX_PRINT_LINE {
+                // Hard-wrapped by TeX, possibly in the middle of a word.
+                message.push_str(next.trim_end());
+            } else if let Some(prefix) = continuation.find(next) {
+                message.push(' ');
+                message.push_str(next[prefix.end()..].trim_end());
+            } else {
+                break;
+            }
+            prev = next;
+            end += 1;
+        }
+        let mut error = TeXError {
+            message: message.trim().into(),
+            line: None,
+            context: vec![],
+        };
+        for (j, next) in lines.iter().enumerate().skip(end).take(MAX_ERROR_CONTEXT) {
+            if next.starts_with("! ") {
+                break;
+            }
+            if let Some(capture) = line_marker.captures(next) {
+                error.line = capture[1].parse().ok();
+                error.context.push(next.to_string());
+                if let Some(rest) = lines.get(j + 1) {
+                    error.context.push(rest.to_string());
+                }
+                break;
+            }
+        }
+        errors.push(error);
+    }
+    errors
+}
+
+#[derive(Clone, Debug)]
+pub struct TeXWarning {
+    /// The warning message, joined into a single line.
+    pub message: String,
+    /// Line in the source file the warning refers to, if any.
+    pub line: Option<usize>,
+}
+
+/// Extracts overfull boxes, LaTeX/package/class warnings and missing characters from a TeX log.
+///
+/// Underfull boxes are ignored because they are harmless for the kind of documents we generate.
+/// So are warnings asking to run LaTeX again, since we always run it once in a fresh directory,
+/// and the summaries at the end of the run repeating earlier warnings.
+pub fn parse_warnings(log: &str) -> Vec<TeXWarning> {
+    let generic_warning = Regex::new(r"^(LaTeX|Package|Class)( \S+)*? Warning: ").unwrap();
+    let ignored = Regex::new(r"(?i)\bre-?run\b|Warning: There (were|was|is|are) ").unwrap();
+    let line_info = Regex::new(r"(?:on input line|at lines?|detected at line) (\d+)").unwrap();
+    let lines = log.lines().collect::<Vec<_>>();
+    let mut warnings = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        let message = if line.starts_with("Overfull \\") || line.starts_with("Missing character: ")
+        {
+            line.to_string()
+        } else if generic_warning.is_match(line) {
+            // Such warnings are wrapped and end with a blank line.
+            let mut message = line.to_string();
+            let mut prev = *line;
+            for next in lines.iter().skip(i + 1).take(MAX_WARNING_LINES - 1) {
+                if next.trim().is_empty() {
+                    break;
+                }
+                if prev.chars().count() < MAX_PRINT_LINE {
+                    // Not hard-wrapped by TeX, so this was an actual line break in the message.
+                    message.push(' ');
+                }
+                prev = next;
+                // Continuation lines of package warnings are prefixed with "(package name)".
+                let next = match next.trim_start().strip_prefix('(') {
+                    Some(rest) => rest.split_once(')').map_or(rest, |(_, rest)| rest),
+                    None => next,
+                };
+                message.push_str(next.trim());
+            }
+            message
+        } else {
+            continue;
+        };
+        if ignored.is_match(&message) {
+            continue;
+        }
+        let line = line_info
+            .captures(&message)
+            .and_then(|capture| capture[1].parse().ok());
+        warnings.push(TeXWarning { message, line });
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn undefined_control_sequence() {
+        // pdflatex
+        let log = indoc! {r"
+            (/usr/share/texlive/texmf-dist/tex/latex/amsfonts/umsa.fd)
+            ! Undefined control sequence.
+            l.12 \(\foo
+                       {x}\)
+            The control sequence at the end of the top line
+            of your error message was never \def'ed. If you have
+            misspelled it (e.g., `\hobx'), type `I' and the correct
+            spelling (e.g., `I\hbox'). Otherwise just continue,
+            and I'll forget about whatever was undefined.
+
+        "};
+        let errors = parse_errors(log);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "Undefined control sequence.");
+        assert_eq!(errors[0].line, Some(12));
+        assert_eq!(errors[0].context, [r"l.12 \(\foo", r"           {x}\)"]);
+    }
+
+    #[test]
+    fn latex_error_with_help() {
+        // pdflatex
+        let log = indoc! {r"
+            ! LaTeX Error: Environment foo undefined.
+
+            See the LaTeX manual or LaTeX Companion for explanation.
+            Type  H <return>  for immediate help.
+             ...                                              
+                                                              
+            l.7 \begin{foo}
+                           
+            Your command was ignored.
+        "};
+        let errors = parse_errors(log);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "LaTeX Error: Environment foo undefined.");
+        assert_eq!(errors[0].line, Some(7));
+    }
+
+    #[test]
+    fn multi_line_package_error() {
+        // xelatex
+        let log = indoc! {r#"
+            ! Package fontspec Error: 
+            (fontspec)                The font "Foo Sans" cannot be found; this may be
+            (fontspec)                but usually is not a fontspec bug. Either there
+            (fontspec)                is a typo in the font name/file, the font is not
+            (fontspec)                installed (correctly), or there is a bug in the
+            (fontspec)                underlying font loading engine
+            (fontspec)                (XeTeX/luaotfload).
+
+            For immediate help type H <return>.
+             ...                                              
+                                                              
+            l.3 \setmainfont{Foo Sans}
+                                      
+        "#};
+        let errors = parse_errors(log);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].message,
+            "Package fontspec Error: The font \"Foo Sans\" cannot be found; this may be but \
+             usually is not a fontspec bug. Either there is a typo in the font name/file, the \
+             font is not installed (correctly), or there is a bug in the underlying font \
+             loading engine (XeTeX/luaotfload)."
+        );
+        assert_eq!(errors[0].line, Some(3));
+    }
+
+    #[test]
+    fn hard_wrapped_error() {
+        // xelatex, with a message longer than max_print_line
+        let log = indoc! {r"
+            ! LaTeX Error: File `some-package-with-a-rather-long-name-that-does-not-exist.s
+            ty' not found.
+
+            Type X to quit or <RETURN> to proceed,
+            or enter new name. (Default extension: sty)
+
+            Enter file name: 
+            ! Emergency stop.
+            <read *> 
+                     
+            l.4 \begin
+                      {document}^^M
+        "};
+        let errors = parse_errors(log);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0].message,
+            "LaTeX Error: File `some-package-with-a-rather-long-name-that-does-not-exist.sty' \
+             not found."
+        );
+        // The next error comes before any line marker.
+        assert_eq!(errors[0].line, None);
+        assert_eq!(errors[1].message, "Emergency stop.");
+        assert_eq!(errors[1].line, Some(4));
+    }
+
+    #[test]
+    fn multiple_errors() {
+        // pdflatex
+        let log = indoc! {r"
+            ! Missing $ inserted.
+            <inserted text> 
+                            $
+            l.9 \(x^
+                    \)
+            I've inserted a begin-math/end-math symbol since I think
+            you left one out. Proceed, with fingers crossed.
+
+            ! Extra }, or forgotten $.
+            l.15 \(\frac{1}{2}}
+                               \)
+            I've deleted a group-closing symbol because it seems to be
+        "};
+        let errors = parse_errors(log);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].message, "Missing $ inserted.");
+        assert_eq!(errors[0].line, Some(9));
+        assert_eq!(errors[1].message, "Extra }, or forgotten $.");
+        assert_eq!(errors[1].line, Some(15));
+    }
+
+    #[test]
+    fn box_warnings() {
+        let log = indoc! {r"
+            Overfull \hbox (15.0pt too wide) in paragraph at lines 12--12
+            []\OT1/cmr/m/n/12 a very long line|
+
+            Underfull \hbox (badness 10000) in paragraph at lines 14--15
+
+            Overfull \vbox (3.0pt too high) has occurred while \output is active []
+        "};
+        let warnings = parse_warnings(log);
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(
+            warnings[0].message,
+            r"Overfull \hbox (15.0pt too wide) in paragraph at lines 12--12"
+        );
+        assert_eq!(warnings[0].line, Some(12));
+        assert_eq!(warnings[1].line, None);
+    }
+
+    #[test]
+    fn undefined_references() {
+        let log = indoc! {r"
+            LaTeX Warning: Reference `eq:foo' on page 1 undefined on input line 14.
+
+            LaTeX Warning: Citation `some-rather-long-citation-key-that-gets-wrapped' on pa
+            ge 1 undefined on input line 22.
+
+            LaTeX Warning: There were undefined references.
+
+        "};
+        let warnings = parse_warnings(log);
+        // The summary is left out.
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(
+            warnings[0].message,
+            "LaTeX Warning: Reference `eq:foo' on page 1 undefined on input line 14."
+        );
+        assert_eq!(warnings[0].line, Some(14));
+        assert_eq!(
+            warnings[1].message,
+            "LaTeX Warning: Citation `some-rather-long-citation-key-that-gets-wrapped' on \
+             page 1 undefined on input line 22."
+        );
+        assert_eq!(warnings[1].line, Some(22));
+    }
+
+    #[test]
+    fn rerun_and_summary_warnings() {
+        let log = indoc! {r"
+            LaTeX Warning: Label `eq:foo' multiply defined.
+
+            Package hyperref Warning: Rerun to get /PageLabels entry.
+
+            LaTeX Warning: There were multiply-defined labels.
+
+            Package rerunfilecheck Warning: File `source.out' has changed.
+            (rerunfilecheck)                Rerun to get outlines right
+            (rerunfilecheck)                or use package `bookmark'.
+
+            LaTeX Warning: Label(s) may have changed. Rerun to get cross-references right.
+
+            Package natbib Warning: There were undefined citations.
+
+        "};
+        let warnings = parse_warnings(log);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].message,
+            "LaTeX Warning: Label `eq:foo' multiply defined."
+        );
+    }
+
+    #[test]
+    fn package_warning() {
+        let log = indoc! {r"
+            Package hyperref Warning: Token not allowed in a PDF string (Unicode):
+            (hyperref)                removing `math shift' on input line 12.
+
+        "};
+        let warnings = parse_warnings(log);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(
+            warnings[0].message,
+            "Package hyperref Warning: Token not allowed in a PDF string (Unicode): removing \
+             `math shift' on input line 12."
+        );
+        assert_eq!(warnings[0].line, Some(12));
+    }
+
+    #[test]
+    fn missing_characters() {
+        // pdflatex, then xelatex
+        let log = indoc! {r"
+            Missing character: There is no ∀ in font cmr12!
+            Missing character: There is no ^^^^2200 (U+2200) in font [lmroman12-regular]:m
+            apping=tex-text;!
+        "};
+        let warnings = parse_warnings(log);
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(
+            warnings[0].message,
+            "Missing character: There is no ∀ in font cmr12!"
+        );
+        assert!(warnings.iter().all(|warning| warning.line.is_none()));
+    }
+}
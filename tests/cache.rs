@@ -0,0 +1,130 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::FakeToolchain;
+use just_latex::{render_fragments, FragmentKind, RenderedFragment};
+
+fn svgs(rendered: &[RenderedFragment]) -> Vec<Vec<String>> {
+    rendered
+        .iter()
+        .map(|fragment| fragment.parts.iter().map(|part| part.svg.clone()).collect())
+        .collect()
+}
+
+const FRAGMENTS: [(FragmentKind, &str); 4] = [
+    (FragmentKind::DontShow, r"\newcommand{\R}{\mathbb{R}}"),
+    (FragmentKind::InlineMath, r"x \in \R"),
+    (FragmentKind::DisplayMath, r"\sum_{k=1}^n k"),
+    (FragmentKind::InlineMath, r"y"),
+];
+
+#[test]
+fn document_cache_hit() {
+    let fake = FakeToolchain::new();
+    let config = fake.build("document");
+    let first = render_fragments(&FRAGMENTS, &config).unwrap();
+    assert_eq!(fake.runs(), 1);
+    let second = render_fragments(&FRAGMENTS, &config).unwrap();
+    assert_eq!(fake.runs(), 1);
+    assert_eq!(svgs(&first), svgs(&second));
+}
+
+#[test]
+fn document_cache_invalidated_by_any_fragment() {
+    let fake = FakeToolchain::new();
+    let config = fake.build("document");
+    render_fragments(&FRAGMENTS, &config).unwrap();
+    let mut edited = FRAGMENTS;
+    edited[3].1 = "z";
+    render_fragments(&edited, &config).unwrap();
+    assert_eq!(fake.runs(), 2);
+}
+
+#[test]
+fn fragment_cache_hit() {
+    let fake = FakeToolchain::new();
+    let config = fake.build("fragment");
+    let first = render_fragments(&FRAGMENTS, &config).unwrap();
+    assert_eq!(fake.runs(), 1);
+    let second = render_fragments(&FRAGMENTS, &config).unwrap();
+    assert_eq!(fake.runs(), 1);
+    assert_eq!(svgs(&first), svgs(&second));
+}
+
+#[test]
+fn fragment_cache_partial_hit() {
+    let fake = FakeToolchain::new();
+    let config = fake.build("fragment");
+    let first = render_fragments(&FRAGMENTS, &config).unwrap();
+    let mut edited = FRAGMENTS;
+    edited[3].1 = "z";
+    let second = render_fragments(&edited, &config).unwrap();
+    assert_eq!(fake.runs(), 2);
+    // Only the edited fragment is compiled again, along with the %dontshow one.
+    let source = fake.last_source();
+    assert!(source.contains(r"\newcommand{\R}{\mathbb{R}}"));
+    assert!(source.contains(r"\(z\)"));
+    assert!(!source.contains(r"x \in \R"));
+    assert!(!source.contains(r"\sum_{k=1}^n k"));
+    assert_eq!(svgs(&first)[..3], svgs(&second)[..3]);
+    assert_eq!(second[3].parts.len(), 1);
+}
+
+#[test]
+fn fragment_cache_invalidated_by_dontshow() {
+    let fake = FakeToolchain::new();
+    let config = fake.build("fragment");
+    render_fragments(&FRAGMENTS, &config).unwrap();
+    let mut edited = FRAGMENTS;
+    edited[0].1 = r"\newcommand{\R}{\mathbf{R}}";
+    render_fragments(&edited, &config).unwrap();
+    assert_eq!(fake.runs(), 2);
+    let source = fake.last_source();
+    assert!(source.contains(r"x \in \R") && source.contains(r"\(y\)"));
+}
+
+#[test]
+fn fragment_cache_invalidated_by_preamble() {
+    let fake = FakeToolchain::new();
+    render_fragments(&FRAGMENTS, &fake.build("fragment")).unwrap();
+    let config = fake
+        .config()
+        .set("cache_granularity", "fragment")
+        .unwrap()
+        .set(
+            "preamble",
+            "\\documentclass{article}\n\\usepackage{amssymb}\n\\begin{document}",
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+    render_fragments(&FRAGMENTS, &config).unwrap();
+    assert_eq!(fake.runs(), 2);
+    let source = fake.last_source();
+    assert!(source.contains(r"x \in \R") && source.contains(r"\(y\)"));
+}
+
+#[test]
+fn fragment_cache_with_many_pages() {
+    // Under preview positioning, every fragment is a page of its own.
+    let sources = (0..12).map(|i| format!("x_{{{}}}", i)).collect::<Vec<_>>();
+    let fragments = sources
+        .iter()
+        .map(|src| (FragmentKind::InlineMath, src.as_str()))
+        .collect::<Vec<_>>();
+    let fake = FakeToolchain::new();
+    let config = fake.build("fragment");
+    let first = render_fragments(&fragments, &config).unwrap();
+    let second = render_fragments(&fragments, &config).unwrap();
+    assert_eq!(fake.runs(), 1);
+    assert_eq!(svgs(&first), svgs(&second));
+
+    let mut edited = fragments.clone();
+    edited[5].1 = "y";
+    render_fragments(&edited, &config).unwrap();
+    assert_eq!(fake.runs(), 2);
+    let source = fake.last_source();
+    assert!(source.contains(r"\(y\)"));
+    assert!(!source.contains("x_{0}") && !source.contains("x_{11}"));
+}
@@ -0,0 +1,16 @@
+use std::process::{Command, Stdio};
+
+#[test]
+fn document_is_not_taken_as_format() {
+    for arg in ["doc.json", "posts/doc", "./doc"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_just-latex"))
+            .arg(arg)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("looks like a file rather than a Pandoc format"));
+        assert!(stderr.contains(&format!("just-latex render {}", arg)));
+    }
+}
@@ -0,0 +1,113 @@
+//! A fake TeX toolchain, so that the pipeline can be tested without a TeX installation.
+//!
+//! The engine and dvisvgm are replaced by shell scripts emulating `positioning = "preview"`: the
+//! engine writes the log lines the preview package and our setup would produce for every fragment
+//! of the source, and dvisvgm prints one page for each of them. Lines of the source containing
+//! `OVERFULL` get an overfull box warning, and labels ask for another run. Sources containing
+//! `FATAL` fail to compile with an error that does not tell the line, and sources containing
+//! `BROKENSVG` fail in dvisvgm.
+
+// Not every test uses everything.
+#![allow(dead_code)]
+
+use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf};
+
+use just_latex::{Config, ConfigBuilder};
+use tempfile::TempDir;
+
+const LATEX: &str = r#"#!/bin/sh
+for arg; do source=$arg; done
+echo run >> "$FAKE_DIR/runs"
+cp "$source" "$FAKE_DIR/last.tex"
+echo 'Preview: Tightpage -32891 -32891 32891 32891' > source.log
+sed -n 's/^\\begin{jlbox[a-z]*}{\([0-9]*\)}.*/JustLaTeX: fragment \1 491520+131072x1310720/p' \
+    "$source" >> source.log
+# Lines containing OVERFULL make an overfull box.
+awk '/OVERFULL/ { printf "Overfull \\hbox (15.0pt too wide) in paragraph at lines %d--%d\n", NR, NR }' \
+    "$source" >> source.log
+# Labels always change in a fresh directory.
+if grep -q '\\label' "$source"; then
+    printf '\nLaTeX Warning: Label(s) may have changed. Rerun to get cross-references right.\n\n' \
+        >> source.log
+fi
+# Sources containing FATAL fail with an error that does not tell the line.
+if grep -q FATAL "$source"; then
+    echo '! TeX capacity exceeded, sorry [main memory size=5000000].' >> source.log
+    exit 1
+fi
+: > source.pdf
+"#;
+
+const DVISVGM: &str = r#"#!/bin/sh
+# Sources containing BROKENSVG cannot be converted.
+if grep -q BROKENSVG source.tex; then
+    echo 'dvisvgm: broken input' >&2
+    exit 1
+fi
+sed -n 's/^JustLaTeX: fragment \([0-9]*\) .*/\1/p' source.log | while read -r i; do
+    cat <<SVG
+<?xml version='1.0' encoding='UTF-8'?>
+<svg version='1.1' xmlns='http://www.w3.org/2000/svg' width='21pt' height='10pt' viewBox='72 72 21 10'>
+<g id='page$i'>
+<path d='M73 73H92V80H73Z'/>
+</g>
+</svg>
+SVG
+done
+"#;
+
+pub struct FakeToolchain {
+    dir: TempDir,
+}
+
+impl FakeToolchain {
+    pub fn new() -> Self {
+        let dir = TempDir::new().unwrap();
+        for (name, script) in [("latex", LATEX), ("dvisvgm", DVISVGM)] {
+            let script = script.replace("$FAKE_DIR", dir.path().to_str().unwrap());
+            let path = dir.path().join(name);
+            fs::write(&path, script).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        Self { dir }
+    }
+
+    pub fn cache_dir(&self) -> PathBuf {
+        self.dir.path().join("cache")
+    }
+
+    /// Starts a configuration using the fake toolchain and a cache in its directory.
+    pub fn config(&self) -> ConfigBuilder {
+        let bin = |name: &str| self.dir.path().join(name).to_str().unwrap().to_string();
+        ConfigBuilder::new()
+            .unwrap()
+            .set("latex", bin("latex"))
+            .unwrap()
+            .set("dvisvgm", bin("dvisvgm"))
+            .unwrap()
+            .set("positioning", "preview")
+            .unwrap()
+            .set("cache_dir", self.cache_dir().to_str().unwrap())
+            .unwrap()
+    }
+
+    pub fn build(&self, granularity: &str) -> Config {
+        self.config()
+            .set("cache_granularity", granularity)
+            .unwrap()
+            .build()
+            .unwrap()
+    }
+
+    /// How many times the engine has run so far.
+    pub fn runs(&self) -> usize {
+        fs::read_to_string(self.dir.path().join("runs"))
+            .map(|runs| runs.lines().count())
+            .unwrap_or_default()
+    }
+
+    /// The source the engine compiled last.
+    pub fn last_source(&self) -> String {
+        fs::read_to_string(self.dir.path().join("last.tex")).unwrap()
+    }
+}
@@ -0,0 +1,17 @@
+#!/bin/sh
+# Regenerates the SyncTeX fixtures from the .tex sources next to this script. Requires pdflatex
+# and xelatex. The paths of the inputs recorded in the files depend on where this runs, which the
+# tests do not care about.
+set -e
+cd "$(dirname "$0")"
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+cp ./*.tex "$work"
+(
+    cd "$work"
+    # Compressed, as pdflatex writes it by default.
+    pdflatex -synctex=1 -interaction=nonstopmode pdflatex.tex > /dev/null
+    # Uncompressed.
+    xelatex -synctex=-1 -interaction=nonstopmode -no-pdf xelatex.tex > /dev/null
+)
+cp "$work/pdflatex.synctex.gz" "$work/xelatex.synctex" .
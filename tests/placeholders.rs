@@ -0,0 +1,57 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::FakeToolchain;
+use just_latex::{render_fragments, Config, FragmentKind};
+
+fn placeholder_config(fake: &FakeToolchain) -> Config {
+    fake.config()
+        .set("on_error", "placeholder")
+        .unwrap()
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn bisection_finds_the_failing_fragment() {
+    let fake = FakeToolchain::new();
+    let fragments = [
+        (FragmentKind::DontShow, r"\newcommand{\R}{\mathbb{R}}"),
+        (FragmentKind::InlineMath, "a"),
+        (FragmentKind::InlineMath, "b"),
+        (FragmentKind::InlineMath, r"\text{FATAL}"),
+        (FragmentKind::InlineMath, "c"),
+        (FragmentKind::DisplayMath, "d"),
+    ];
+    let rendered = render_fragments(&fragments, &placeholder_config(&fake)).unwrap();
+    // The log does not tell the line of the error.
+    let error = rendered[3].error.as_deref().unwrap();
+    assert!(error.contains("TeX capacity exceeded"));
+    assert!(rendered[3].parts.is_empty());
+    for i in [1, 2, 4, 5] {
+        assert!(rendered[i].error.is_none());
+        assert_eq!(rendered[i].parts.len(), 1);
+    }
+    assert!(rendered[0].error.is_none());
+    assert!(!fake.last_source().contains("FATAL"));
+}
+
+#[test]
+fn bisection_ignores_errors_after_latex() {
+    let fake = FakeToolchain::new();
+    let fragments = [
+        (FragmentKind::InlineMath, "a"),
+        (FragmentKind::InlineMath, r"\text{BROKENSVG}"),
+        (FragmentKind::InlineMath, r"\text{FATAL}"),
+        (FragmentKind::InlineMath, "c"),
+    ];
+    // The prefix ending with BROKENSVG compiles, so FATAL is left out. dvisvgm then fails on the
+    // rest, which is not the fault of any fragment.
+    let error = render_fragments(&fragments, &placeholder_config(&fake))
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("fail to run dvisvgm"));
+    assert!(fake.last_source().contains("BROKENSVG"));
+    assert!(!fake.last_source().contains("FATAL"));
+}
@@ -0,0 +1,93 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::FakeToolchain;
+use just_latex::{render, render_fragments, FragmentKind};
+use serde_json::json;
+
+const FRAGMENTS: [(FragmentKind, &str); 2] = [
+    (FragmentKind::InlineMath, "x"),
+    (FragmentKind::InlineMath, r"\text{OVERFULL}"),
+];
+
+#[test]
+fn warnings_are_not_fatal_by_default() {
+    let fake = FakeToolchain::new();
+    let rendered = render_fragments(&FRAGMENTS, &fake.build("document")).unwrap();
+    assert!(rendered.iter().all(|fragment| fragment.error.is_none()));
+}
+
+#[test]
+fn strict_mode_fails_on_warnings() {
+    let fake = FakeToolchain::new();
+    let config = fake.config().set("strict", true).unwrap().build().unwrap();
+    let error = render_fragments(&FRAGMENTS, &config)
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains(r"in inline math fragment `\text{OVERFULL}`:"));
+    assert!(error.contains(r"Overfull \hbox (15.0pt too wide)"));
+    assert!(!error.contains("fragment `x`"));
+}
+
+#[test]
+fn strict_mode_with_placeholders() {
+    let fake = FakeToolchain::new();
+    let config = fake
+        .config()
+        .set("strict", true)
+        .unwrap()
+        .set("on_error", "placeholder")
+        .unwrap()
+        .build()
+        .unwrap();
+    let rendered = render_fragments(&FRAGMENTS, &config).unwrap();
+    assert!(rendered[0].error.is_none());
+    assert_eq!(rendered[0].parts.len(), 1);
+    let error = rendered[1].error.as_deref().unwrap();
+    assert!(error.contains(r"Overfull \hbox (15.0pt too wide)"));
+}
+
+#[test]
+fn strict_mode_ignores_rerun_warnings() {
+    let fake = FakeToolchain::new();
+    let config = fake
+        .config()
+        .set("strict", true)
+        .unwrap()
+        .set("on_error", "placeholder")
+        .unwrap()
+        .build()
+        .unwrap();
+    let fragments = [
+        (FragmentKind::DisplayMath, r"x \label{eq:x}"),
+        (FragmentKind::DisplayMath, r"y \label{eq:y}"),
+    ];
+    let rendered = render_fragments(&fragments, &config).unwrap();
+    assert!(rendered.iter().all(|fragment| fragment.error.is_none()));
+    assert_eq!(fake.runs(), 1);
+}
+
+#[test]
+fn warnings_are_reported() {
+    let fake = FakeToolchain::new();
+    let config = fake.build("document");
+    let document = json!({
+        "pandoc-api-version": [1, 23, 1],
+        "meta": {},
+        "blocks": [{"t": "Para", "c": [
+            {"t": "Math", "c": [{"t": "InlineMath"}, "x"]},
+            {"t": "Math", "c": [{"t": "InlineMath"}, r"\text{OVERFULL}"]},
+        ]}],
+    });
+    let report = render(&mut document.clone(), &config).unwrap();
+    assert!(report.failed.is_empty());
+    assert_eq!(report.warnings.len(), 1);
+    assert!(report.warnings[0].starts_with(
+        r"in inline math fragment `\text{OVERFULL}`: Overfull \hbox (15.0pt too wide)"
+    ));
+    // The warnings are cached along with the rendering.
+    let cached = render(&mut document.clone(), &config).unwrap();
+    assert_eq!(cached.warnings, report.warnings);
+    assert_eq!(fake.runs(), 1);
+}